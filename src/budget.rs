@@ -0,0 +1,93 @@
+// Execution limits for running untrusted programs
+// One Budget is shared by an env and all the child envs created under it,
+// and gets reset at the start of every top-level evaluation
+use crate::error::{BlisprResult, Error, Result};
+use std::{
+	cell::Cell,
+	time::{Duration, Instant},
+};
+
+// Every limit is off by default
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Limits {
+	// evaluation steps - one per lval_eval call, or one per op on the VM
+	pub max_steps: Option<u64>,
+	// nested function calls
+	pub max_depth: Option<usize>,
+	// total cells in every value returned by a function call - a value passed back up
+	// through several calls is counted once for each of them, so this is cells returned, not allocated
+	pub max_cells: Option<usize>,
+	// wall-clock time for the whole evaluation
+	pub timeout: Option<Duration>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Budget {
+	limits: Limits,
+	steps: Cell<u64>,
+	depth: Cell<usize>,
+	cells: Cell<usize>,
+	deadline: Cell<Option<Instant>>,
+}
+
+impl Budget {
+	pub fn new(limits: Limits) -> Self {
+		Self {
+			limits,
+			..Self::default()
+		}
+	}
+
+	pub fn limits(&self) -> Limits {
+		self.limits
+	}
+
+	// start counting from zero and restart the clock
+	pub fn reset(&self) {
+		self.steps.set(0);
+		self.depth.set(0);
+		self.cells.set(0);
+		self.deadline
+			.set(self.limits.timeout.map(|t| Instant::now() + t));
+	}
+
+	// charge one evaluation step, and check the clock while we're here
+	pub fn step(&self) -> Result<()> {
+		let steps = self.steps.get() + 1;
+		self.steps.set(steps);
+		if let Some(max) = self.limits.max_steps {
+			if steps > max {
+				return Err(Error::StepLimit(max));
+			}
+		}
+		if let Some(deadline) = self.deadline.get() {
+			if Instant::now() >= deadline {
+				return Err(Error::Timeout(self.limits.timeout.unwrap_or_default()));
+			}
+		}
+		Ok(())
+	}
+
+	// run a function call one level deeper, charging for the cells it returns
+	pub fn call(&self, f: impl FnOnce() -> BlisprResult) -> BlisprResult {
+		let depth = self.depth.get() + 1;
+		if let Some(max) = self.limits.max_depth {
+			if depth > max {
+				return Err(Error::DepthLimit(max));
+			}
+		}
+		self.depth.set(depth);
+		let ret = f();
+		self.depth.set(depth - 1);
+		let ret = ret?;
+		// counting walks the whole value, so only pay for it when there's a limit
+		if let Some(max) = self.limits.max_cells {
+			let cells = self.cells.get() + ret.cells();
+			self.cells.set(cells);
+			if cells > max {
+				return Err(Error::CellLimit(max));
+			}
+		}
+		Ok(ret)
+	}
+}
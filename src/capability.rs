@@ -0,0 +1,94 @@
+// Builtins that reach outside the interpreter are grouped into capabilities
+// A sandboxed env can deny any of them - calling a denied builtin is an error
+use crate::error::{Error, Result};
+use std::fmt;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+	// exiting or otherwise controlling the host process
+	Process,
+	Filesystem,
+	// reading or writing environment variables
+	Environment,
+	Clock,
+	Random,
+}
+
+impl Capability {
+	const ALL: [Capability; 5] = [
+		Capability::Process,
+		Capability::Filesystem,
+		Capability::Environment,
+		Capability::Clock,
+		Capability::Random,
+	];
+
+	fn bit(self) -> u8 {
+		1 << (self as u8)
+	}
+
+	// the capability a builtin needs, if any
+	pub fn required_by(builtin: &str) -> Option<Self> {
+		match builtin {
+			"exit" => Some(Capability::Process),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for Capability {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match self {
+			Capability::Process => "process",
+			Capability::Filesystem => "filesystem",
+			Capability::Environment => "environment",
+			Capability::Clock => "clock",
+			Capability::Random => "random",
+		};
+		write!(f, "{name}")
+	}
+}
+
+// A set of granted capabilities
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+	pub fn all() -> Self {
+		Capability::ALL
+			.iter()
+			.fold(Self::none(), |acc, c| acc.with(*c))
+	}
+
+	pub fn none() -> Self {
+		Self(0)
+	}
+
+	#[must_use]
+	pub fn with(self, c: Capability) -> Self {
+		Self(self.0 | c.bit())
+	}
+
+	#[must_use]
+	pub fn without(self, c: Capability) -> Self {
+		Self(self.0 & !c.bit())
+	}
+
+	pub fn allows(self, c: Capability) -> bool {
+		self.0 & c.bit() != 0
+	}
+
+	// Ok if the named builtin may be called under this set
+	pub fn check(self, builtin: &str) -> Result<()> {
+		match Capability::required_by(builtin) {
+			Some(c) if !self.allows(c) => Err(Error::PermissionDenied(builtin.to_string(), c)),
+			_ => Ok(()),
+		}
+	}
+}
+
+impl Default for Capabilities {
+	fn default() -> Self {
+		Self::all()
+	}
+}
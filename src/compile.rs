@@ -0,0 +1,157 @@
+// Compile parsed Lvals down to a flat list of ops for the VM in vm.rs
+// Symbols bound as lambda formals are resolved to slots up front
+// everything else is looked up by name through the Lenv chain, same as lval_eval
+use crate::lval::{quoted, sexpr, Lval};
+use std::{cell::RefCell, fmt, rc::Rc};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+	// push a copy of constants[i]
+	Const(usize),
+	// push the value bound to names[i], looked up through the environment
+	Load(usize),
+	// push the value held in local slot i
+	Local(usize),
+	// pop n arguments and then a function, push the result of the call
+	Call(usize),
+	// throw away the top of the stack
+	Pop,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Chunk {
+	pub code: Vec<Op>,
+	pub constants: Vec<Lval>,
+	pub names: Vec<String>,
+	pub locals: Vec<String>,
+}
+
+impl Chunk {
+	fn new(locals: &[String]) -> Self {
+		Self {
+			locals: locals.to_vec(),
+			..Self::default()
+		}
+	}
+
+	fn constant(&mut self, v: &Lval) -> usize {
+		self.constants.push(v.clone());
+		self.constants.len() - 1
+	}
+
+	// names are deduplicated, so each symbol only gets one pool entry per chunk
+	fn name(&mut self, s: &str) -> usize {
+		if let Some(i) = self.names.iter().position(|n| n == s) {
+			i
+		} else {
+			self.names.push(s.to_string());
+			self.names.len() - 1
+		}
+	}
+
+	fn emit(&mut self, v: &Lval) {
+		// 'x is data, not code
+		if let Lval::Sexpr(cells) = v {
+			if let Some(x) = quoted(cells) {
+				let i = self.constant(x);
+				self.code.push(Op::Const(i));
+				return;
+			}
+		}
+		match v {
+			Lval::Blispr(forms) => {
+				// each form leaves one value behind - keep only the last
+				if forms.is_empty() {
+					let i = self.constant(&sexpr());
+					self.code.push(Op::Const(i));
+				}
+				for (i, form) in forms.iter().enumerate() {
+					if i > 0 {
+						self.code.push(Op::Pop);
+					}
+					self.emit(form);
+				}
+			},
+			Lval::Sym(s) => {
+				let op = match self.locals.iter().position(|l| l == s) {
+					Some(slot) => Op::Local(slot),
+					None => Op::Load(self.name(s)),
+				};
+				self.code.push(op);
+			},
+			Lval::Sexpr(cells) => match cells.len() {
+				// empty sexprs evaluate to themselves
+				0 => {
+					let i = self.constant(v);
+					self.code.push(Op::Const(i));
+				},
+				// a single expression is just evaluated, never called
+				1 => self.emit(&cells[0]),
+				// otherwise call the first cell on the rest
+				n => {
+					for cell in cells {
+						self.emit(cell);
+					}
+					self.code.push(Op::Call(n - 1));
+				},
+			},
+			// anything else evaluates to itself
+			Lval::Char(_) | Lval::Fun(_) | Lval::Keyword(_) | Lval::Num(_) | Lval::Qexpr(_) => {
+				let i = self.constant(v);
+				self.code.push(Op::Const(i));
+			},
+		}
+	}
+}
+
+impl fmt::Display for Chunk {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (i, op) in self.code.iter().enumerate() {
+			write!(f, "{i:04} ")?;
+			match op {
+				Op::Const(c) => writeln!(f, "CONST {c} ({})", self.constants[*c])?,
+				Op::Load(n) => writeln!(f, "LOAD {n} ({})", self.names[*n])?,
+				Op::Local(l) => writeln!(f, "LOCAL {l} ({})", self.locals[*l])?,
+				Op::Call(n) => writeln!(f, "CALL {n}")?,
+				Op::Pop => writeln!(f, "POP")?,
+			}
+		}
+		Ok(())
+	}
+}
+
+// Compile a top-level form, or a lambda body with its formals as locals
+pub fn compile(v: &Lval, locals: &[String]) -> Chunk {
+	let mut ret = Chunk::new(locals);
+	ret.emit(v);
+	ret
+}
+
+// A lambda's body, compiled the first time the VM calls it
+// Every copy of the lambda shares the one chunk, so later calls skip straight to running it
+#[derive(Debug, Default, Clone)]
+pub struct CompiledBody(Rc<RefCell<Option<Rc<Chunk>>>>);
+
+impl CompiledBody {
+	pub fn chunk(&self, body: &Lval, locals: &[String]) -> Rc<Chunk> {
+		let mut cached = self.0.borrow_mut();
+		// a partial application can leave different formals to bind, and they'd get different slots
+		if let Some(chunk) = cached.as_ref().filter(|c| c.locals == locals) {
+			return Rc::clone(chunk);
+		}
+		// the body is a qexpr, run as an sexpr
+		let chunk = Rc::new(match body {
+			Lval::Qexpr(cells) => compile(&Lval::Sexpr(cells.clone()), locals),
+			_ => compile(body, locals),
+		});
+		*cached = Some(Rc::clone(&chunk));
+		chunk
+	}
+}
+
+// a lambda is the same lambda whether or not it's been compiled yet
+impl PartialEq for CompiledBody {
+	fn eq(&self, _other: &Self) -> bool {
+		true
+	}
+}
@@ -0,0 +1,142 @@
+// REPL settings, read from the config file and then overridden by any CLI flags
+// The file is plain `key = value` lines, # at the start of a line or after a space starts a comment:
+//
+//   history = ~/blispr-history.txt
+//   history-size = 500
+//   prompt = λ>
+//   edit-mode = vi
+//   banner = false
+use crate::{
+	error::{Error, Result},
+	Opt,
+};
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum EditMode {
+	Emacs,
+	Vi,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+	pub history: PathBuf,
+	pub history_size: usize,
+	pub prompt: String,
+	pub edit_mode: EditMode,
+	pub banner: bool,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		// one history for the whole user, not one per directory the REPL was started in
+		let history = dirs::data_dir().map_or_else(
+			|| PathBuf::from(".blispr-history.txt"),
+			|d| d.join("blispr").join("history.txt"),
+		);
+		Self {
+			history,
+			history_size: 100,
+			prompt: "blispr> ".to_string(),
+			edit_mode: EditMode::Emacs,
+			banner: true,
+		}
+	}
+}
+
+// keep a trailing space so the cursor isn't jammed against the prompt
+fn pad_prompt(prompt: &str) -> String {
+	format!("{} ", prompt.trim_end())
+}
+
+// # starts a comment at the start of a line or after a space, so values can still have one in them
+fn strip_comment(line: &str) -> &str {
+	let mut prev = ' ';
+	for (i, c) in line.char_indices() {
+		if c == '#' && prev.is_whitespace() {
+			return &line[..i];
+		}
+		prev = c;
+	}
+	line
+}
+
+// ~ at the start of a path means the home directory
+fn expand_home(path: &str) -> PathBuf {
+	match (path.strip_prefix("~/"), dirs::home_dir()) {
+		(Some(rest), Some(home)) => home.join(rest),
+		_ => PathBuf::from(path),
+	}
+}
+
+impl Config {
+	// the user's config file if there is one, then the flags in opt on top
+	pub fn load(opt: &Opt) -> Result<Self> {
+		let path = dirs::config_dir().map(|d| d.join("blispr").join("config"));
+		Self::load_from(path.as_deref(), opt)
+	}
+
+	// the config file at path if there is one, then the flags in opt on top
+	pub fn load_from(path: Option<&Path>, opt: &Opt) -> Result<Self> {
+		let mut ret = Self::default();
+		if let Some(path) = path.filter(|path| path.exists()) {
+			ret.parse(&fs::read_to_string(path)?)?;
+		}
+		if let Some(history) = &opt.history {
+			ret.history.clone_from(history);
+		}
+		if let Some(size) = opt.history_size {
+			ret.history_size = size;
+		}
+		if let Some(prompt) = &opt.prompt {
+			ret.prompt = pad_prompt(prompt);
+		}
+		if let Some(mode) = opt.edit_mode {
+			ret.edit_mode = mode;
+		}
+		if opt.no_banner {
+			ret.banner = false;
+		}
+		Ok(ret)
+	}
+
+	pub fn parse(&mut self, s: &str) -> Result<()> {
+		for (i, line) in s.lines().enumerate() {
+			let line = strip_comment(line).trim();
+			if line.is_empty() {
+				continue;
+			}
+			let bad = |what: &str| Error::Config(format!("line {}: {what}", i + 1));
+			let (key, value) = line
+				.split_once('=')
+				.ok_or_else(|| bad("expected key = value"))?;
+			let value = value.trim();
+			match key.trim() {
+				"history" => self.history = expand_home(value),
+				"history-size" => {
+					self.history_size = value
+						.parse()
+						.map_err(|_| bad("history-size must be a number"))?;
+				},
+				"prompt" => self.prompt = pad_prompt(value),
+				"edit-mode" => {
+					self.edit_mode = match value {
+						"emacs" => EditMode::Emacs,
+						"vi" => EditMode::Vi,
+						_ => return Err(bad("edit-mode must be emacs or vi")),
+					};
+				},
+				"banner" => {
+					self.banner = value
+						.parse()
+						.map_err(|_| bad("banner must be true or false"))?;
+				},
+				key => return Err(bad(&format!("unknown setting {key}"))),
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,396 @@
+// An interactive step debugger for the tree-walker
+// When one is attached to the Lenv, lval_eval hands it every function call, and it decides
+// whether to stop before making it and ask what to do next
+// Line breakpoints match the line the reader found each call's opening bracket on
+use crate::{
+	error::{BlisprResult, Result},
+	eval::lval_call,
+	lenv::Lenv,
+	lval::{Func, Lval},
+	parse::{eval_form, read_str},
+	Backend,
+};
+use std::{
+	cell::RefCell,
+	fmt,
+	io::{self, BufRead, BufReader, Write},
+};
+
+const HELP: &str = "\
+s, step              step into this call
+n, next              step over this call
+o, out               run until the function we're in returns
+c, continue          run to the next breakpoint
+args                 show the arguments this call is getting
+env                  show the environment, innermost first
+bt                   show the call we stopped at, and the ones we're inside
+b, break [name|line] set a breakpoint, or list them
+d, delete name|line  remove a breakpoint
+p, print expr        evaluate expr here
+q, quit              remove every breakpoint and run to the end
+h, help              show this message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+	// a call to the function bound to this name
+	Function(String),
+	// a call written on this line of the file being run
+	Line(usize),
+}
+
+impl Breakpoint {
+	// a number is a line, anything else is a function name
+	pub fn parse(s: &str) -> Self {
+		match s.parse() {
+			Ok(line) => Breakpoint::Line(line),
+			Err(_) => Breakpoint::Function(s.to_string()),
+		}
+	}
+}
+
+impl fmt::Display for Breakpoint {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Breakpoint::Function(name) => write!(f, "{name}"),
+			Breakpoint::Line(line) => write!(f, "line {line}"),
+		}
+	}
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Mode {
+	// only stop at breakpoints
+	#[default]
+	Run,
+	// stop at the very next call
+	Step,
+	// stop at the next call made at this depth or shallower
+	Next(usize),
+	// stop at the next call made shallower than this
+	Out(usize),
+}
+
+#[derive(Debug, Default)]
+struct State {
+	breakpoints: Vec<Breakpoint>,
+	mode: Mode,
+	// the calls we're inside, outermost first - only kept while there's something to stop for
+	stack: Vec<Lval>,
+	// line numbers only mean something in code that came from a file
+	lines: bool,
+	// line of the last call we looked at - a line breakpoint stops on arriving at its line,
+	// not at every call along it
+	last_line: Option<usize>,
+	// nothing evaluated from the prompt stops
+	suspended: bool,
+}
+
+pub struct Debugger {
+	input: RefCell<Box<dyn BufRead>>,
+	output: RefCell<Box<dyn Write>>,
+	state: RefCell<State>,
+}
+
+// Lenv is Debug and PartialEq, and this hangs off it
+impl fmt::Debug for Debugger {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Debugger")
+			.field("state", &self.state)
+			.finish_non_exhaustive()
+	}
+}
+
+impl PartialEq for Debugger {
+	fn eq(&self, other: &Self) -> bool {
+		std::ptr::eq(self, other)
+	}
+}
+
+// The line a call was read from, if it was read at all
+fn call_line(expr: &Lval) -> Option<usize> {
+	match expr {
+		Lval::Sexpr(cells) => cells.line,
+		_ => None,
+	}
+}
+
+// The name a call was made through, like fib in (fib 10)
+fn call_name(expr: &Lval) -> Option<&str> {
+	match expr {
+		Lval::Sexpr(cells) => match cells.first().map(|c| &**c) {
+			Some(Lval::Sym(name)) => Some(name),
+			_ => None,
+		},
+		_ => None,
+	}
+}
+
+impl Debugger {
+	pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+		Self {
+			input: RefCell::new(input),
+			output: RefCell::new(output),
+			state: RefCell::default(),
+		}
+	}
+
+	// Commands from stdin, shown on stdout
+	pub fn stdio() -> Self {
+		Self::new(
+			Box::new(BufReader::new(io::stdin())),
+			Box::new(io::stdout()),
+		)
+	}
+
+	pub fn add_breakpoint(&self, breakpoint: Breakpoint) {
+		let mut state = self.state.borrow_mut();
+		if !state.breakpoints.contains(&breakpoint) {
+			state.breakpoints.push(breakpoint);
+		}
+	}
+
+	// false if there wasn't one
+	pub fn remove_breakpoint(&self, breakpoint: &Breakpoint) -> bool {
+		let mut state = self.state.borrow_mut();
+		let before = state.breakpoints.len();
+		state.breakpoints.retain(|b| b != breakpoint);
+		state.breakpoints.len() != before
+	}
+
+	pub fn clear_breakpoints(&self) {
+		self.state.borrow_mut().breakpoints.clear();
+	}
+
+	pub fn breakpoints(&self) -> Vec<Breakpoint> {
+		self.state.borrow().breakpoints.clone()
+	}
+
+	// stop at the next call
+	pub fn step(&self) {
+		self.state.borrow_mut().mode = Mode::Step;
+	}
+
+	// Done with this input - stop stepping, and stop matching lines
+	pub fn finish(&self) {
+		let mut state = self.state.borrow_mut();
+		state.mode = Mode::Run;
+		state.stack.clear();
+		state.lines = false;
+		state.last_line = None;
+	}
+
+	// What runs next comes from a file, so line breakpoints apply to it
+	pub fn enter_file(&self) {
+		let mut state = self.state.borrow_mut();
+		state.lines = true;
+		state.last_line = None;
+	}
+
+	// Make the call that expr evaluated to, stopping first if a breakpoint or a step says to
+	pub fn call(&self, e: &mut Lenv, expr: &Lval, f: Lval, args: &mut Lval) -> BlisprResult {
+		// with nothing to stop for, stay out of the way
+		if self.idle() {
+			return lval_call(e, f, args);
+		}
+		if let Some(reason) = self.should_stop(e, expr) {
+			let depth = self.state.borrow().stack.len();
+			self.say(format_args!("stopped at {expr} ({reason})"))?;
+			self.prompt(e, depth, Some((expr, args)))?;
+		}
+		self.state.borrow_mut().stack.push(expr.clone());
+		let ret = lval_call(e, f, args);
+		self.state.borrow_mut().stack.pop();
+		ret
+	}
+
+	fn idle(&self) -> bool {
+		let state = self.state.borrow();
+		state.breakpoints.is_empty() && state.mode == Mode::Run
+	}
+
+	// Stop where (break ()) was called
+	// Calls made while the debugger was idle aren't on the stack, so bt and out only see the ones since
+	pub fn pause(&self, e: &mut Lenv) -> Result<()> {
+		if self.state.borrow().suspended {
+			return Ok(());
+		}
+		// stepping is relative to where break was called from, not the break call itself
+		let depth = {
+			let stack = &self.state.borrow().stack;
+			let in_break = stack.last().and_then(call_name) == Some("break");
+			stack.len() - usize::from(in_break)
+		};
+		self.say(format_args!("stopped at (break)"))?;
+		self.prompt(e, depth, None)
+	}
+
+	fn should_stop(&self, e: &Lenv, expr: &Lval) -> Option<String> {
+		let mut state = self.state.borrow_mut();
+		let name = call_name(expr);
+		// (break ()) stops by itself
+		if state.suspended || name == Some("break") {
+			return None;
+		}
+		let line = call_line(expr).filter(|_| state.lines);
+		let arrived = line != state.last_line;
+		state.last_line = line;
+		let depth = state.stack.len();
+		let stepping = match state.mode {
+			Mode::Run => false,
+			Mode::Step => true,
+			Mode::Next(d) => depth <= d,
+			Mode::Out(d) => depth < d,
+		};
+		if stepping {
+			Some("step".to_string())
+		} else if let Some(name) = name.filter(|name| {
+			// a formal of the same name hides the function
+			state
+				.breakpoints
+				.contains(&Breakpoint::Function((*name).to_string()))
+				&& e.get(name).is_ok_and(|v| matches!(*v, Lval::Fun(_)))
+		}) {
+			Some(format!("breakpoint {name}"))
+		} else {
+			let line = line.filter(|_| arrived)?;
+			state
+				.breakpoints
+				.contains(&Breakpoint::Line(line))
+				.then(|| format!("breakpoint line {line}"))
+		}
+	}
+
+	fn say(&self, s: impl fmt::Display) -> Result<()> {
+		let mut output = self.output.borrow_mut();
+		writeln!(output, "{s}")?;
+		output.flush()?;
+		Ok(())
+	}
+
+	// Take commands until one of them carries on running
+	// depth is how many calls deep the stop is, for stepping over and out
+	// call is the one about to be made and its arguments, unless we stopped at (break ())
+	fn prompt(&self, e: &mut Lenv, depth: usize, call: Option<(&Lval, &Lval)>) -> Result<()> {
+		self.state.borrow_mut().mode = Mode::Run;
+		loop {
+			{
+				let mut output = self.output.borrow_mut();
+				write!(output, "debug> ")?;
+				output.flush()?;
+			}
+			let mut line = String::new();
+			// with nobody left to ask, run to the end
+			if self.input.borrow_mut().read_line(&mut line)? == 0 {
+				self.say("")?;
+				self.clear_breakpoints();
+				return Ok(());
+			}
+			let line = line.trim();
+			let (cmd, arg) = line
+				.split_once(' ')
+				.map_or((line, ""), |(cmd, arg)| (cmd, arg.trim()));
+			let mode = match (cmd, arg) {
+				("s" | "step", _) => Mode::Step,
+				("n" | "next", _) => Mode::Next(depth),
+				("o" | "out", _) => Mode::Out(depth),
+				("c" | "continue", _) => Mode::Run,
+				("q" | "quit", _) => {
+					self.clear_breakpoints();
+					Mode::Run
+				},
+				_ => {
+					self.command(e, cmd, arg, call)?;
+					continue;
+				},
+			};
+			self.state.borrow_mut().mode = mode;
+			return Ok(());
+		}
+	}
+
+	// the commands that look around without carrying on
+	fn command(
+		&self,
+		e: &mut Lenv,
+		cmd: &str,
+		arg: &str,
+		call: Option<(&Lval, &Lval)>,
+	) -> Result<()> {
+		match (cmd, arg) {
+			("", _) => Ok(()),
+			("args", _) => match call {
+				Some((_, Lval::Sexpr(cells))) if !cells.is_empty() => {
+					for (i, arg) in cells.iter().enumerate() {
+						self.say(format_args!("{}: {arg}", i + 1))?;
+					}
+					Ok(())
+				},
+				_ => self.say("no arguments"),
+			},
+			("env", _) => self.print_env(e),
+			("bt", _) => {
+				let state = self.state.borrow();
+				let stack = state.stack.iter().chain(call.map(|(expr, _)| expr));
+				for (i, call) in stack.rev().enumerate() {
+					self.say(format_args!("#{i} {call}"))?;
+				}
+				Ok(())
+			},
+			("b" | "break", "") => {
+				let breakpoints = self.breakpoints();
+				if breakpoints.is_empty() {
+					return self.say("no breakpoints");
+				}
+				for breakpoint in breakpoints {
+					self.say(breakpoint)?;
+				}
+				Ok(())
+			},
+			("b" | "break", target) => {
+				self.add_breakpoint(Breakpoint::parse(target));
+				Ok(())
+			},
+			("d" | "delete", target) if !target.is_empty() => {
+				if self.remove_breakpoint(&Breakpoint::parse(target)) {
+					Ok(())
+				} else {
+					self.say(format_args!("no breakpoint at {target}"))
+				}
+			},
+			("p" | "print", expr) if !expr.is_empty() => {
+				self.state.borrow_mut().suspended = true;
+				let ret = read_str(expr).and_then(|mut v| eval_form(e, &mut v, Backend::Tree));
+				self.state.borrow_mut().suspended = false;
+				match ret {
+					Ok(v) => self.say(v),
+					Err(err) => self.say(format_args!("Error: {err}")),
+				}
+			},
+			("h" | "help", _) => self.say(HELP),
+			_ => self.say(format_args!("unknown command {cmd} - h for help")),
+		}
+	}
+
+	// each env from the innermost out, leaving out the builtins
+	fn print_env(&self, e: &Lenv) -> Result<()> {
+		let mut env = Some(e);
+		let mut i = 0;
+		while let Some(level) = env {
+			let mut bindings: Vec<String> = level
+				.entries()
+				.filter(|(_, v)| !matches!(v, Lval::Fun(Func::Builtin(..))))
+				.map(|(k, v)| format!("{k} = {v}"))
+				.collect();
+			bindings.sort();
+			let scope = if level.parent.is_some() {
+				"local"
+			} else {
+				"global"
+			};
+			self.say(format_args!("#{i} {scope}: {}", bindings.join(", ")))?;
+			env = level.parent;
+			i += 1;
+		}
+		Ok(())
+	}
+}
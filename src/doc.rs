@@ -0,0 +1,78 @@
+// Signatures and one-line descriptions for the builtins registered in Lenv::new
+// Aliases get their own entry so every bound name can be looked up directly
+
+// (name, arguments, description)
+#[rustfmt::skip]
+const BUILTINS: &[(&str, &str, &str)] = &[
+	// Definition
+	("\\", "{formals} {body}", "Build a lambda - prefix the last formal with & to collect the rest"),
+	("def", "{names} values...", "Bind each name to the value in the same position"),
+	// List manipulation
+	("cons", "x {list}", "Attach x to the front of list"),
+	("eval", "{expr}", "Evaluate a Q-Expression as an S-Expression"),
+	("head", "{list}", "The first element of list"),
+	("init", "{list}", "Everything in list but the last element"),
+	("list", "xs...", "Collect the arguments into a Q-Expression"),
+	("quote", "x", "x itself, unevaluated - 'x is short for (quote x)"),
+	("join", "{lists}...", "Join Q-Expressions together"),
+	("len", "{list}", "Number of elements in list"),
+	("tail", "{list}", "Everything in list but the first element"),
+	// Utility
+	("break", "()", "Stop in the debugger here, if one is attached"),
+	("exit", "() | status", "Leave the program, with an optional exit status"),
+	("gc", "()", "Free unreachable lambda environments now, and return heap statistics"),
+	("printenv", "()", "List every binding in the current environment"),
+	// Arithmetic
+	("+", "x y...", "Add numbers"),
+	("add", "x y...", "Add numbers"),
+	("-", "x y...", "Subtract numbers, or negate a single number"),
+	("sub", "x y...", "Subtract numbers, or negate a single number"),
+	("*", "x y...", "Multiply numbers"),
+	("mul", "x y...", "Multiply numbers"),
+	("/", "x y...", "Divide numbers"),
+	("div", "x y...", "Divide numbers"),
+	("^", "x y...", "Raise x to the power of y"),
+	("pow", "x y...", "Raise x to the power of y"),
+	("%", "x y...", "Remainder of dividing x by y"),
+	("rem", "x y...", "Remainder of dividing x by y"),
+	("min", "x y...", "The smallest number"),
+	("max", "x y...", "The largest number"),
+	// Comparison
+	("==", "x y", "1 if x and y are the same value, otherwise 0"),
+	// Keywords
+	("keyword?", "x", "1 if x is a :keyword, otherwise 0"),
+	("keyword->str", ":keyword", "The keyword's name without its colon, as a symbol"),
+	// Characters
+	("char->int", "c", "The unicode code point of c"),
+	("int->char", "n", "The character with code point n"),
+	("char-alphabetic?", "c", "1 if c is a letter, otherwise 0"),
+	("char-numeric?", "c", "1 if c is a digit, otherwise 0"),
+	("str->chars", "symbol", "The characters of a symbol's name, as a Q-Expression"),
+	("chars->str", "{chars}", "A symbol named by a non-empty list of characters"),
+];
+
+fn lookup(name: &str) -> Option<&'static (&'static str, &'static str, &'static str)> {
+	BUILTINS.iter().find(|(n, ..)| *n == name)
+}
+
+// The arguments a builtin expects, like "x {list}"
+pub fn signature(name: &str) -> Option<&'static str> {
+	lookup(name).map(|(_, args, _)| *args)
+}
+
+// What a builtin does, in a sentence
+pub fn describe(name: &str) -> Option<&'static str> {
+	lookup(name).map(|(_, _, description)| *description)
+}
+
+// How many arguments a builtin takes, if it's always the same number
+// Read off the signature - "x y..." takes any number, and "() | status" is one argument either way
+pub fn arity(name: &str) -> Option<usize> {
+	let args = signature(name)?;
+	if args.contains("...") {
+		return None;
+	}
+	args.split(" | ")
+		.next()
+		.map(|first| first.split_whitespace().count())
+}
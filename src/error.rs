@@ -1,42 +1,78 @@
-use crate::lval::Lval;
+use crate::{capability::Capability, lval::Lval, reader::Diagnostic};
 use std::{
 	cmp::Ord,
 	fmt::{self, Debug},
 	hash::Hash,
 	marker::Copy,
 	string::ToString,
+	time::Duration,
 };
 
 #[derive(Debug)]
 pub enum Error {
+	CellLimit(usize),
+	Command(String),
+	Config(String),
+	DepthLimit(usize),
 	DivideByZero,
 	EmptyList,
+	// not really an error - (exit) unwinds the evaluation with a status code
+	Exit(i32),
 	FunctionFormat,
+	// (form number, line, column, what went wrong) when a script is run form by form
+	InForm(usize, usize, usize, Box<Error>),
+	// the input ran out partway through a form, at (line, column)
+	Incomplete(usize, usize),
+	Io(String),
 	NoChildren,
 	NotANumber,
 	NumArguments(usize, usize),
 	Parse(String),
+	PermissionDenied(String, Capability),
 	Readline(String),
+	StepLimit(u64),
+	// everything a recovering read found wrong
+	Syntax(Vec<Diagnostic>),
+	Timeout(Duration),
 	WrongType(String, String),
 	UnknownFunction(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+// Process exit statuses for a script that fails - 2 is left to clap for bad usage
+pub const EXIT_RUNTIME: i32 = 1;
+pub const EXIT_PARSE: i32 = 3;
+pub const EXIT_IO: i32 = 4;
 pub type BlisprResult = Result<Box<Lval>>;
 
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		use Error::{
-			DivideByZero, EmptyList, FunctionFormat, NoChildren, NotANumber, NumArguments, Parse,
-			Readline, UnknownFunction, WrongType,
+			CellLimit, Command, Config, DepthLimit, DivideByZero, EmptyList, Exit, FunctionFormat,
+			InForm, Incomplete, Io, NoChildren, NotANumber, NumArguments, Parse, PermissionDenied,
+			Readline, StepLimit, Syntax, Timeout, UnknownFunction, WrongType,
 		};
 		match self {
+			CellLimit(max) => write!(f, "Cell limit exceeded: returned more than {max} cells"),
+			Command(s) => write!(f, "Command error: {s}"),
+			Config(s) => write!(f, "Config error: {s}"),
+			DepthLimit(max) => write!(f, "Recursion limit exceeded: more than {max} nested calls"),
 			DivideByZero => write!(f, "Divide by zero"),
 			EmptyList => write!(f, "Empty list"),
+			Exit(status) => write!(f, "Exit with status {status}"),
 			FunctionFormat => write!(
 				f,
 				"Function format invalid.  Symbol '&' not followed by a single symbol"
 			),
+			InForm(n, line, col, error) => {
+				write!(f, "Form {n} at line {line}, column {col}: {error}")
+			},
+			Incomplete(line, col) => write!(
+				f,
+				"Parse error: line {line}, column {col}: unexpected end of input"
+			),
+			Io(s) => write!(f, "IO error: {s}"),
 			NoChildren => write!(f, "Lval has no children"),
 			NotANumber => write!(f, "NaN"),
 			NumArguments(expected, received) => write!(
@@ -44,7 +80,20 @@ impl fmt::Display for Error {
 				"Wrong number of arguments: expected {expected}, received {received}"
 			),
 			Parse(s) => write!(f, "Parse error: {s}"),
+			PermissionDenied(name, capability) => write!(
+				f,
+				"Permission denied: {name} needs the {capability} capability"
+			),
 			Readline(s) => write!(f, "Readline error: {s}"),
+			StepLimit(max) => write!(f, "Step limit exceeded: more than {max} evaluation steps"),
+			Syntax(diagnostics) => {
+				write!(f, "Parse error:")?;
+				for diagnostic in diagnostics {
+					write!(f, "\n  {diagnostic}")?;
+				}
+				Ok(())
+			},
+			Timeout(t) => write!(f, "Timed out after {}ms", t.as_millis()),
 			WrongType(expected, received) => write!(
 				f,
 				"Wrong type: expected {expected}, received {received}"
@@ -54,6 +103,19 @@ impl fmt::Display for Error {
 	}
 }
 
+impl Error {
+	// The status the process should exit with when this error ends a script
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			Error::Exit(status) => *status,
+			Error::InForm(.., error) => error.exit_code(),
+			Error::Parse(_) | Error::Incomplete(..) | Error::Syntax(_) => EXIT_PARSE,
+			Error::Io(_) | Error::Readline(_) => EXIT_IO,
+			_ => EXIT_RUNTIME,
+		}
+	}
+}
+
 impl<T> From<pest::error::Error<T>> for Error
 where
 	T: Debug + Ord + Copy + Hash,
@@ -63,15 +125,9 @@ where
 	}
 }
 
-impl From<std::num::ParseIntError> for Error {
-	fn from(_error: std::num::ParseIntError) -> Self {
-		Error::NotANumber
-	}
-}
-
 impl From<std::io::Error> for Error {
 	fn from(error: std::io::Error) -> Self {
-		Error::Parse(error.to_string())
+		Error::Io(error.to_string())
 	}
 }
 
@@ -1,7 +1,10 @@
 use crate::{
-	error::{BlisprResult, Error},
+	error::{BlisprResult, Error, Result},
+	gc,
 	lenv::Lenv,
-	lval::{add, join, lambda, num, pop, qexpr, sexpr, Func, Lval},
+	lval::{
+		add, character, join, keyword, lambda, num, pop, qexpr, quoted, sexpr, sym, Func, Lval,
+	},
 };
 use log::debug;
 use std::{
@@ -15,7 +18,6 @@ macro_rules! apply_binop {
 		match (*$x, *$y) {
 			(Lval::Num(x_num), Lval::Num(y_num)) => {
 				$x = num(x_num.$op(y_num));
-				continue;
 			},
 			_ => return Err(Error::NotANumber),
 		}
@@ -60,7 +62,7 @@ fn builtin_op(v: &mut Lval, func: &str) -> BlisprResult {
 				if y.as_num()? == 0 {
 					debug!("builtin_op: Failed divide {} by {}", x, y);
 					return Err(Error::DivideByZero);
-				};
+				}
 				debug!("builtin_op: Divide {} by {}", x, y);
 				apply_binop!(div, x, y);
 			},
@@ -86,7 +88,7 @@ fn builtin_op(v: &mut Lval, func: &str) -> BlisprResult {
 					x = num(x_num);
 				} else {
 					x = num(y_num);
-				};
+				}
 			},
 			"max" => {
 				debug!("builtin_op: Max {} and {}", x, y);
@@ -96,7 +98,7 @@ fn builtin_op(v: &mut Lval, func: &str) -> BlisprResult {
 					x = num(x_num);
 				} else {
 					x = num(y_num);
-				};
+				}
 			},
 			_ => unreachable!(),
 		}
@@ -181,7 +183,7 @@ fn builtin_var(e: &mut Lenv, a: &mut Lval, func: &str) -> BlisprResult {
 //}
 
 // FOR NOW def IS LOCAL ENV ASSIGN
-fn builtin_def(e: &mut Lenv, v: &mut Lval) -> BlisprResult {
+pub fn builtin_def(e: &mut Lenv, v: &mut Lval) -> BlisprResult {
 	builtin_var(e, v, "def")
 }
 
@@ -227,11 +229,8 @@ pub fn builtin_eval_stub(_v: &mut Lval) -> BlisprResult {
 pub fn builtin_eval(e: &mut Lenv, v: &mut Lval) -> BlisprResult {
 	let qexpr = pop(v, 0)?;
 	if let Lval::Qexpr(ref children) = *qexpr {
-		let mut new_sexpr = sexpr();
-		for c in children {
-			let cloned = Box::new(*c.clone());
-			add(&mut new_sexpr, &cloned)?;
-		}
+		// keeping the line it was read from, so line breakpoints find it
+		let mut new_sexpr = Box::new(Lval::Sexpr(children.clone()));
 		debug!("builtin_eval: {:?}", new_sexpr);
 		lval_eval(e, &mut new_sexpr)
 	} else {
@@ -242,10 +241,172 @@ pub fn builtin_eval(e: &mut Lenv, v: &mut Lval) -> BlisprResult {
 }
 
 // terminate the program (or exit the prompt)
-pub fn builtin_exit(_v: &mut Lval) -> BlisprResult {
-	// always succeeds
-	println!("Goodbye!");
-	::std::process::exit(0);
+// this unwinds as an error all the way back out to run(), carrying the status code
+pub fn builtin_exit(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	let status = pop(v, 0)?;
+	match *status {
+		// (exit ()) is a plain successful exit
+		Lval::Sexpr(ref children) if children.is_empty() => Err(Error::Exit(0)),
+		// the OS only keeps the low byte, so anything bigger would exit with the wrong status
+		Lval::Num(n @ 0..=255) => Err(Error::Exit(
+			i32::try_from(n).map_err(|_| Error::NotANumber)?,
+		)),
+		Lval::Num(n) => Err(Error::WrongType(
+			"exit status from 0 to 255".to_string(),
+			n.to_string(),
+		)),
+		_ => Err(Error::WrongType(
+			"number or ()".to_string(),
+			format!("{status:?}"),
+		)),
+	}
+}
+
+// collect garbage now, and return the heap statistics as {:live n :allocated n :collected n :collections n}
+pub fn builtin_gc(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	gc::collect();
+	let stats = gc::stats();
+	let mut ret = qexpr();
+	for (name, n) in [
+		("live", stats.live),
+		("allocated", stats.allocated),
+		("collected", stats.collected),
+		("collections", stats.collections),
+	] {
+		add(&mut ret, &keyword(name))?;
+		add(&mut ret, &num(i64::try_from(n).unwrap_or(i64::MAX)))?;
+	}
+	Ok(ret)
+}
+
+// (quote x) never gets here - lval_eval and the compiler return x unevaluated
+// This only runs when quote is called through another name, like (def {q} quote) - by then x is already evaluated
+pub fn builtin_quote(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	pop(v, 0)
+}
+
+// 1 if the two arguments are the same value, otherwise 0
+pub fn builtin_eq(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 2 {
+		return Err(Error::NumArguments(2, child_count));
+	}
+	let x = pop(v, 0)?;
+	let y = pop(v, 0)?;
+	Ok(num(i64::from(x == y)))
+}
+
+// 1 if the argument is a :keyword, otherwise 0
+pub fn builtin_is_keyword(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	Ok(num(i64::from(matches!(*pop(v, 0)?, Lval::Keyword(_)))))
+}
+
+// The name of a keyword without its colon
+// There's no string type, so it comes back as a symbol
+pub fn builtin_keyword_to_str(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	let k = pop(v, 0)?;
+	match *k {
+		Lval::Keyword(ref name) => Ok(sym(name)),
+		_ => Err(Error::WrongType("keyword".to_string(), format!("{k:?}"))),
+	}
+}
+
+// The single character argument to one of the char builtins
+fn char_arg(v: &mut Lval) -> Result<char> {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	let c = pop(v, 0)?;
+	match *c {
+		Lval::Char(c) => Ok(c),
+		_ => Err(Error::WrongType("character".to_string(), format!("{c:?}"))),
+	}
+}
+
+// The unicode code point of a character
+pub fn builtin_char_to_int(v: &mut Lval) -> BlisprResult {
+	Ok(num(i64::from(u32::from(char_arg(v)?))))
+}
+
+pub fn builtin_int_to_char(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	let n = pop(v, 0)?.as_num()?;
+	match u32::try_from(n).ok().and_then(char::from_u32) {
+		Some(c) => Ok(character(c)),
+		None => Err(Error::WrongType(
+			"character code".to_string(),
+			n.to_string(),
+		)),
+	}
+}
+
+pub fn builtin_char_alphabetic(v: &mut Lval) -> BlisprResult {
+	Ok(num(i64::from(char_arg(v)?.is_alphabetic())))
+}
+
+pub fn builtin_char_numeric(v: &mut Lval) -> BlisprResult {
+	Ok(num(i64::from(char_arg(v)?.is_numeric())))
+}
+
+// There's no string type - symbols stand in for text, so these convert between symbols and char lists
+pub fn builtin_str_to_chars(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	let s = pop(v, 0)?.as_string()?;
+	let mut ret = qexpr();
+	for c in s.chars() {
+		add(&mut ret, &character(c))?;
+	}
+	Ok(ret)
+}
+
+pub fn builtin_chars_to_str(v: &mut Lval) -> BlisprResult {
+	let child_count = v.len()?;
+	if child_count != 1 {
+		return Err(Error::NumArguments(1, child_count));
+	}
+	let chars = pop(v, 0)?;
+	match *chars {
+		// there's no symbol with an empty name to print or read back
+		Lval::Qexpr(ref children) if children.is_empty() => Err(Error::EmptyList),
+		Lval::Qexpr(ref children) => {
+			let s = children
+				.iter()
+				.map(|c| match **c {
+					Lval::Char(c) => Ok(c),
+					_ => Err(Error::WrongType("character".to_string(), format!("{c:?}"))),
+				})
+				.collect::<Result<String>>()?;
+			Ok(sym(&s))
+		},
+		_ => Err(Error::WrongType("qexpr".to_string(), format!("{chars:?}"))),
+	}
 }
 
 // Return the first element of a qexpr
@@ -359,7 +520,8 @@ pub fn builtin_len(v: &mut Lval) -> BlisprResult {
 			match *qexpr {
 				Lval::Qexpr(_) => {
 					debug!("Returning length of {qexpr:?}");
-					Ok(num(qexpr.len()? as i64))
+					let len = i64::try_from(qexpr.len()?).map_err(|_| Error::NotANumber)?;
+					Ok(num(len))
 				},
 				_ => Err(Error::WrongType(
 					"qexpr".to_string(),
@@ -371,6 +533,19 @@ pub fn builtin_len(v: &mut Lval) -> BlisprResult {
 	}
 }
 
+#[allow(clippy::unnecessary_wraps)]
+pub fn builtin_break_stub(_v: &mut Lval) -> BlisprResult {
+	Ok(sexpr())
+}
+
+// Stop in the debugger, if there is one
+pub fn builtin_break(e: &mut Lenv) -> BlisprResult {
+	if let Some(debugger) = e.debugger() {
+		debugger.pause(e)?;
+	}
+	Ok(sexpr())
+}
+
 #[allow(clippy::unnecessary_wraps)]
 pub fn builtin_printenv_stub(_v: &mut Lval) -> BlisprResult {
 	Ok(sexpr())
@@ -402,83 +577,96 @@ pub fn builtin_tail(v: &mut Lval) -> BlisprResult {
 	}
 }
 
+// Bind args to the symbols in formals, consuming both from the front
+// Returns the new bindings - formals is left holding anything still unbound
+pub fn bind_formals(formals: &mut Lval, args: &mut Lval) -> Result<HashMap<String, Box<Lval>>> {
+	let mut new_env: HashMap<String, Box<Lval>> = HashMap::new();
+	let given = args.len()?;
+	let total = formals.len()?;
+
+	while args.len()? > 0 {
+		// if we've run out of args to bind, error
+		if formals.len()? == 0 {
+			return Err(Error::NumArguments(total, given));
+		}
+
+		// grab first symbol from formals
+		let sym = pop(formals, 0)?;
+
+		// special case to handle '&'
+		if &sym.as_string()? == "&" {
+			// make sure there's one symbol left
+			if formals.len()? != 1 {
+				return Err(Error::FunctionFormat);
+			}
+
+			// next formal should be found to remaining args
+			let next_sym = pop(formals, 0)?;
+			let arglist = builtin_list(args)?;
+			let curr = new_env
+				.entry(next_sym.as_string()?)
+				.or_insert_with(|| arglist.clone());
+			if *curr != arglist {
+				curr.clone_from(&arglist);
+			}
+			break;
+		}
+
+		// grab next argument from list
+		let val = pop(args, 0)?;
+
+		// bind a copy to the function's environment
+		debug!("bind_formals: adding {sym},{val} to local fn environment");
+		let curr = new_env
+			.entry(sym.as_string()?)
+			.or_insert_with(|| val.clone());
+		// if we're overwriting, overwrite!
+		if *curr != val {
+			curr.clone_from(&val);
+		}
+	}
+	Ok(new_env)
+}
+
 // Call a Lval::Fun(f) on an argument list
 // This will handle both builtins and lambdas
 pub fn lval_call(e: &mut Lenv, f: Lval, args: &mut Lval) -> BlisprResult {
+	e.budget().call(|| lval_call_unmetered(e, f, args))
+}
+
+fn lval_call_unmetered(e: &mut Lenv, f: Lval, args: &mut Lval) -> BlisprResult {
 	match f {
 		Lval::Fun(func) => {
 			match func {
 				// if its one of the ones that need an environment, intercept and route to the properly typed fn
-				Func::Builtin(name, fp) => match name.as_str() {
-					"eval" => builtin_eval(e, args),
-					"def" => builtin_def(e, args),
-					//"=" => builtin_put(e, args),
-					"printenv" => builtin_printenv(e),
-					// Otherwise, just apply the actual stored function pointer
-					_ => fp(args),
+				Func::Builtin(name, fp) => {
+					// sandboxed envs may not be allowed to call this one at all
+					e.capabilities().check(&name)?;
+					match name.as_str() {
+						"eval" => builtin_eval(e, args),
+						"def" => builtin_def(e, args),
+						//"=" => builtin_put(e, args),
+						"printenv" => builtin_printenv(e),
+						"break" => builtin_break(e),
+						// Otherwise, just apply the actual stored function pointer
+						_ => fp(args),
+					}
 				},
-				Func::Lambda(env, mut formals, body) => {
+				Func::Lambda(env, mut formals, body, _) => {
 					debug!(
 						"Executing lambda.  Environment: {:?}, Formals: {:?}, body: {:?}",
 						env, formals, body
 					);
 					// If it's a Lambda, bind arguments to a new local environment
-
-					// First, build the lookup hashmap
-					let mut new_env: HashMap<String, Box<Lval>> = HashMap::new();
-					// grab the argument and body
-					let given = args.len()?;
-					let total = formals.len()?;
-
-					while args.len()? > 0 {
-						// if we've run out of args to bind, error
-						if formals.len()? == 0 {
-							return Err(Error::NumArguments(total, given));
-						}
-
-						// grab first symbol from formals
-						let sym = pop(&mut formals, 0)?;
-
-						// special case to handle '&'
-						if &sym.as_string()? == "&" {
-							// make sure there's one symbol left
-							if formals.len()? != 1 {
-								return Err(Error::FunctionFormat);
-							}
-
-							// next formal should be found to remaining args
-							let next_sym = pop(&mut formals, 0)?;
-							let arglist = builtin_list(args)?;
-							let curr = new_env
-								.entry(next_sym.as_string()?)
-								.or_insert_with(|| arglist.clone());
-							if *curr != arglist {
-								*curr = arglist.clone();
-							}
-							break;
-						}
-
-						// grab next argument from list
-						let val = pop(args, 0)?;
-
-						// bind a copy to the function's environment
-						debug!("lval_call: adding {},{} to local fn environment", sym, val);
-						let curr = new_env
-							.entry(sym.as_string()?)
-							.or_insert_with(|| val.clone());
-						// if we're overwriting, overwrite!
-						if *curr != val {
-							*curr = val.clone();
-						}
-					}
+					let new_env = bind_formals(&mut formals, args)?;
 					// Use the lookup map to initialize the new child env for evaluation
 					let mut local_env = Lenv::new(Some(new_env.clone()), Some(e));
 					// if all formals have been bound
 					if formals.len()? == 0 {
 						// Evaluate and return
 						// first, apply any held by the lambda.
-						for (k, v) in env {
-							local_env.put(k, v);
+						for (k, v) in env.bindings().borrow().iter() {
+							local_env.put(k.clone(), v.clone());
 						}
 						let mut ret = sexpr();
 						add(&mut ret, &body)?;
@@ -500,21 +688,16 @@ pub fn lval_call(e: &mut Lenv, f: Lval, args: &mut Lval) -> BlisprResult {
 
 // Given a slice of boxed Lvals, return a single evaluated sexpr
 fn eval_cells(e: &mut Lenv, cells: &[Box<Lval>]) -> BlisprResult {
-	cells.iter().fold(Ok(sexpr()), |acc, c| {
-		match acc {
-			Ok(mut lval) => {
-				add(&mut lval, &*lval_eval(e, &mut c.clone())?)?;
-				Ok(lval)
-			},
-			// it's just a Result so we can bubble errors out of the fold
-			Err(_) => unreachable!(),
-		}
+	cells.iter().try_fold(sexpr(), |mut lval, c| {
+		add(&mut lval, &*lval_eval(e, &mut c.clone())?)?;
+		Ok(lval)
 	})
 }
 
 // Fully evaluate an `Lval`
 #[allow(clippy::module_name_repetitions)]
 pub fn lval_eval(e: &mut Lenv, v: &mut Lval) -> BlisprResult {
+	e.budget().step()?;
 	let child_count;
 	let mut args_eval;
 	match v {
@@ -522,6 +705,10 @@ pub fn lval_eval(e: &mut Lenv, v: &mut Lval) -> BlisprResult {
 			// If it's multiple, evaluate each and return the result of the last
 			args_eval = eval_cells(e, forms)?;
 			let forms_len = args_eval.len()?;
+			if forms_len == 0 {
+				// nothing at all, like an empty file
+				return Ok(sexpr());
+			}
 			return pop(&mut args_eval, forms_len - 1);
 		},
 		Lval::Sym(s) => {
@@ -535,6 +722,11 @@ pub fn lval_eval(e: &mut Lenv, v: &mut Lval) -> BlisprResult {
 			return Ok(result);
 		},
 		Lval::Sexpr(ref mut cells) => {
+			// (quote x) is the one form whose argument isn't evaluated
+			if let Some(x) = quoted(cells) {
+				debug!("lval_eval: quoted {}", x);
+				return Ok(Box::new(x.clone()));
+			}
 			// If it's a Sexpr, we're going to continue past this match
 			// First, though, recursively evaluate each child with lval_eval()
 			debug!("lval_eval: Sexpr, evaluating children");
@@ -561,6 +753,9 @@ pub fn lval_eval(e: &mut Lenv, v: &mut Lval) -> BlisprResult {
 		// lval_call will handle typechecking fp
 		let fp = pop(&mut args_eval, 0)?;
 		debug!("Calling function {:?} on {:?}", fp, v);
-		lval_call(e, *fp, &mut args_eval)
+		match e.debugger() {
+			Some(debugger) => debugger.call(e, v, *fp, &mut args_eval),
+			None => lval_call(e, *fp, &mut args_eval),
+		}
 	}
 }
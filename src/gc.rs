@@ -0,0 +1,213 @@
+// A tracing collector for the environments lambdas carry around
+// Each one is reference counted, so it's usually freed as soon as the last lambda holding it goes
+// A cycle - an env holding a lambda that holds the same env - never gets there, and that's what collect() is for
+// It uses trial deletion, like CPython: take the references envs hold to each other off their counts,
+// and whatever still has some left is in use from outside the heap - the Lenv chain, or a value being evaluated
+// Everything those reach is kept and the rest is garbage, so it's safe to collect at any point in a program
+// Nothing in the language changes an env once it's made, so no blispr program can build a cycle today -
+// the collector is a safety net, for embedders that fill envs in by hand and for when mutable cells arrive
+use crate::lval::{Func, Lval};
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	fmt,
+	rc::{Rc, Weak},
+};
+
+pub type Bindings = HashMap<String, Box<Lval>>;
+
+// how many envs are allocated between automatic collections, unless an embedder says otherwise
+pub const DEFAULT_THRESHOLD: usize = 10_000;
+
+// Tuning for embedders
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+	// envs allocated between automatic collections - None only collects when asked to
+	pub threshold: Option<usize>,
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Self {
+			threshold: Some(DEFAULT_THRESHOLD),
+		}
+	}
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+	// envs on the heap right now
+	pub live: usize,
+	// envs ever allocated
+	pub allocated: usize,
+	// envs freed by the collector - the rest went when their counts reached zero
+	pub collected: usize,
+	pub collections: usize,
+}
+
+#[derive(Default)]
+struct Heap {
+	// every env allocated, dead ones included until the next collection or the list fills up
+	objects: Vec<Weak<RefCell<Bindings>>>,
+	settings: Settings,
+	allocated: usize,
+	collected: usize,
+	collections: usize,
+	since_collection: usize,
+}
+
+thread_local! {
+	static HEAP: RefCell<Heap> = RefCell::new(Heap::default());
+}
+
+// A lambda's environment - copies of the lambda share it
+#[derive(Clone)]
+pub struct Gc(Rc<RefCell<Bindings>>);
+
+impl Gc {
+	pub fn new(bindings: Bindings) -> Self {
+		let ret = Self(Rc::new(RefCell::new(bindings)));
+		let due = HEAP.with(|heap| {
+			let mut heap = heap.borrow_mut();
+			// forget the dead before growing, so the list stays in proportion to what's live
+			if heap.objects.len() == heap.objects.capacity() {
+				heap.objects.retain(|o| o.strong_count() > 0);
+			}
+			heap.objects.push(Rc::downgrade(&ret.0));
+			heap.allocated += 1;
+			heap.since_collection += 1;
+			heap.settings
+				.threshold
+				.is_some_and(|t| heap.since_collection >= t)
+		});
+		if due {
+			collect();
+		}
+		ret
+	}
+
+	pub fn bindings(&self) -> &RefCell<Bindings> {
+		&self.0
+	}
+}
+
+impl fmt::Debug for Gc {
+	// just the names - a value can lead back to this env
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.0.try_borrow() {
+			Ok(bindings) => f.debug_set().entries(bindings.keys()).finish(),
+			Err(_) => write!(f, "{{..}}"),
+		}
+	}
+}
+
+impl PartialEq for Gc {
+	fn eq(&self, other: &Self) -> bool {
+		Rc::ptr_eq(&self.0, &other.0) || *self.0.borrow() == *other.0.borrow()
+	}
+}
+
+// Change how the collector runs on this thread
+pub fn configure(settings: Settings) {
+	HEAP.with(|heap| heap.borrow_mut().settings = settings);
+}
+
+pub fn stats() -> Stats {
+	HEAP.with(|heap| {
+		let heap = heap.borrow();
+		Stats {
+			live: heap.objects.iter().filter(|o| o.strong_count() > 0).count(),
+			allocated: heap.allocated,
+			collected: heap.collected,
+			collections: heap.collections,
+		}
+	})
+}
+
+// Every env a value refers to directly - not the ones those envs refer to in turn
+fn envs_in(v: &Lval, out: &mut Vec<*const RefCell<Bindings>>) {
+	match v {
+		Lval::Blispr(cells) | Lval::Sexpr(cells) | Lval::Qexpr(cells) => {
+			for cell in cells {
+				envs_in(cell, out);
+			}
+		},
+		Lval::Fun(Func::Lambda(env, formals, body, _)) => {
+			out.push(Rc::as_ptr(&env.0));
+			envs_in(formals, out);
+			envs_in(body, out);
+		},
+		Lval::Char(_)
+		| Lval::Fun(Func::Builtin(..))
+		| Lval::Keyword(_)
+		| Lval::Num(_)
+		| Lval::Sym(_) => {},
+	}
+}
+
+// Free every env that only other garbage refers to, returning how many there were
+pub fn collect() -> usize {
+	let envs: Vec<Rc<RefCell<Bindings>>> = HEAP.with(|heap| {
+		let mut heap = heap.borrow_mut();
+		heap.objects.retain(|o| o.strong_count() > 0);
+		heap.since_collection = 0;
+		heap.objects.iter().filter_map(Weak::upgrade).collect()
+	});
+	let index: HashMap<*const RefCell<Bindings>, usize> = envs
+		.iter()
+		.enumerate()
+		.map(|(i, env)| (Rc::as_ptr(env), i))
+		.collect();
+	// references from outside the heap - less the one envs is holding
+	let mut outside: Vec<usize> = envs.iter().map(|env| Rc::strong_count(env) - 1).collect();
+	let mut edges: Vec<Vec<usize>> = Vec::with_capacity(envs.len());
+	for (i, env) in envs.iter().enumerate() {
+		let mut targets = Vec::new();
+		match env.try_borrow() {
+			Ok(bindings) => {
+				for v in bindings.values() {
+					envs_in(v, &mut targets);
+				}
+			},
+			// someone's changing it right now, so it's certainly in use
+			Err(_) => outside[i] += 1,
+		}
+		edges.push(
+			targets
+				.iter()
+				.filter_map(|t| index.get(t).copied())
+				.collect(),
+		);
+	}
+	for targets in &edges {
+		for &t in targets {
+			outside[t] = outside[t].saturating_sub(1);
+		}
+	}
+	// mark everything reachable from the envs in use outside
+	let mut marked = vec![false; envs.len()];
+	let mut pending: Vec<usize> = (0..envs.len()).filter(|&i| outside[i] > 0).collect();
+	while let Some(i) = pending.pop() {
+		if !marked[i] {
+			marked[i] = true;
+			pending.extend(&edges[i]);
+		}
+	}
+	// emptying the garbage breaks its cycles, and the counts free the rest
+	// it's dropped after the loop, so nothing is freed while we're still looking at it
+	let garbage: Vec<Bindings> = envs
+		.iter()
+		.zip(&marked)
+		.filter(|(_, marked)| !**marked)
+		.map(|(env, _)| std::mem::take(&mut *env.borrow_mut()))
+		.collect();
+	let freed = garbage.len();
+	drop(garbage);
+	drop(envs);
+	HEAP.with(|heap| {
+		let mut heap = heap.borrow_mut();
+		heap.collected += freed;
+		heap.collections += 1;
+	});
+	freed
+}
@@ -0,0 +1,337 @@
+// rustyline hooks for the REPL - completion, hints and highlighting come from the global env
+use crate::{
+	doc::signature,
+	lenv::Lenv,
+	lval::{Func, Lval},
+	parse::{comment_len, int_value, is_incomplete},
+	reader::is_symbol_char,
+	session::command_text,
+};
+use rustyline::{
+	completion::{Completer, FilenameCompleter, Pair},
+	highlight::Highlighter,
+	hint::Hinter,
+	validate::{ValidationContext, ValidationResult, Validator},
+	Cmd, ConditionalEventHandler, Context, Event, EventContext, Helper, RepeatCount, Result,
+};
+use std::{borrow::Cow, collections::BTreeMap};
+
+// ANSI styles for each kind of token
+const NUMBER: &str = "\x1b[33m";
+const KEYWORD: &str = "\x1b[34m";
+const CHARACTER: &str = "\x1b[33m";
+const BUILTIN: &str = "\x1b[36m";
+const SYMBOL: &str = "\x1b[32m";
+const UNKNOWN: &str = "\x1b[4;31m";
+const COMMENT: &str = "\x1b[90m";
+const QEXPR_BRACE: &str = "\x1b[35m";
+const MATCHING_BRACKET: &str = "\x1b[1;7m";
+const HINT: &str = "\x1b[90m";
+const RESET: &str = "\x1b[0m";
+
+// The prompt in front of every line of a form after the first
+pub const CONTINUATION: &str = "...> ";
+
+// true if Enter should start another line of input rather than run it
+// :commands are always a single line
+fn needs_more(input: &str) -> bool {
+	command_text(input).is_none() && is_incomplete(input)
+}
+
+// rustyline has no prompt for the lines after the first, so Enter indents them as wide as CONTINUATION,
+// and the highlighter draws the prompt over the indent - the cursor lines up because the width's the same
+fn draw_continuations(line: &str) -> String {
+	let indent = format!("\n{}", " ".repeat(CONTINUATION.len()));
+	line.replace(&indent, &format!("\n{CONTINUATION}"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+	Comment,
+	Char,
+	Open(char),
+	Close(char),
+	Word,
+	Other,
+}
+
+// Split a line into (start, end, token) spans, covering every byte
+// An unterminated comment runs to the end of the line
+fn tokenize(line: &str) -> Vec<(usize, usize, Token)> {
+	let mut ret = Vec::new();
+	let mut chars = line.char_indices().peekable();
+	while let Some((start, c)) = chars.next() {
+		let (end, token) = if let Some((len, _)) = comment_len(&line[start..]) {
+			let end = start + len;
+			while chars.peek().is_some_and(|(i, _)| *i < end) {
+				chars.next();
+			}
+			(end, Token::Comment)
+		} else if line[start..].starts_with("#_") {
+			chars.next();
+			(start + 2, Token::Comment)
+		} else if line[start..].starts_with("#\\") {
+			// a character literal - #\( isn't a bracket
+			chars.next();
+			let mut end = start + 2;
+			if let Some((i, c)) = chars.next() {
+				end = i + c.len_utf8();
+			}
+			while let Some((i, c)) = chars.peek() {
+				if !is_symbol_char(*c) {
+					break;
+				}
+				end = i + c.len_utf8();
+				chars.next();
+			}
+			(end, Token::Char)
+		} else if c == '(' || c == '{' {
+			(start + 1, Token::Open(c))
+		} else if c == ')' || c == '}' {
+			(start + 1, Token::Close(c))
+		} else if is_symbol_char(c) {
+			let mut end = start + c.len_utf8();
+			while let Some((i, c)) = chars.peek() {
+				if !is_symbol_char(*c) {
+					break;
+				}
+				end = i + c.len_utf8();
+				chars.next();
+			}
+			(end, Token::Word)
+		} else {
+			(start + c.len_utf8(), Token::Other)
+		};
+		ret.push((start, end, token));
+	}
+	ret
+}
+
+// Index of the bracket token at or just before pos, and the index of its partner
+fn matching_brackets(tokens: &[(usize, usize, Token)], pos: usize) -> Option<(usize, usize)> {
+	let is_bracket = |i: usize| matches!(tokens[i].2, Token::Open(_) | Token::Close(_));
+	let under = tokens
+		.iter()
+		.position(|(s, _, _)| *s == pos)
+		.filter(|i| is_bracket(*i));
+	let before = tokens
+		.iter()
+		.position(|(_, e, _)| *e == pos)
+		.filter(|i| is_bracket(*i));
+	let cursor = under.or(before)?;
+
+	let mut stack = Vec::new();
+	for (i, (_, _, token)) in tokens.iter().enumerate() {
+		match token {
+			Token::Open(_) => stack.push(i),
+			Token::Close(_) => {
+				if let Some(open) = stack.pop() {
+					if open == cursor {
+						return Some((open, i));
+					}
+					if i == cursor {
+						return Some((i, open));
+					}
+				}
+			},
+			_ => {},
+		}
+	}
+	None
+}
+
+struct Binding {
+	builtin: bool,
+	// the arguments it takes, if it's a function
+	args: Option<String>,
+}
+
+pub struct BlisprHelper {
+	names: BTreeMap<String, Binding>,
+	filenames: FilenameCompleter,
+	color: bool,
+}
+
+impl BlisprHelper {
+	pub fn new(e: &Lenv, color: bool) -> Self {
+		let mut ret = Self {
+			names: BTreeMap::new(),
+			filenames: FilenameCompleter::new(),
+			color,
+		};
+		ret.refresh(e);
+		ret
+	}
+
+	// pick up anything defined since the last line
+	pub fn refresh(&mut self, e: &Lenv) {
+		self.names = e
+			.entries()
+			.map(|(k, v)| {
+				let binding = match v {
+					Lval::Fun(Func::Builtin(name, _)) => Binding {
+						builtin: true,
+						args: signature(name).map(ToString::to_string),
+					},
+					Lval::Fun(Func::Lambda(_, formals, ..)) => Binding {
+						builtin: false,
+						args: Some(formals.to_string()),
+					},
+					_ => Binding {
+						builtin: false,
+						args: None,
+					},
+				};
+				(k.to_string(), binding)
+			})
+			.collect();
+	}
+
+	// Words inside a qexpr are data, so they're never flagged as unknown
+	fn word_style(&self, word: &str, quoted: bool) -> Option<&'static str> {
+		if int_value(word).is_some() {
+			return Some(NUMBER);
+		}
+		if word.len() > 1 && word.starts_with(':') {
+			return Some(KEYWORD);
+		}
+		match self.names.get(word) {
+			Some(Binding { builtin: true, .. }) => Some(BUILTIN),
+			Some(_) => Some(SYMBOL),
+			None if quoted => None,
+			None => Some(UNKNOWN),
+		}
+	}
+}
+
+impl Completer for BlisprHelper {
+	type Candidate = Pair;
+
+	fn complete(
+		&self,
+		line: &str,
+		pos: usize,
+		ctx: &Context<'_>,
+	) -> rustyline::Result<(usize, Vec<Pair>)> {
+		// the argument to :load and :save is a path
+		if line.starts_with(":load ") || line.starts_with(":save ") {
+			return self.filenames.complete(line, pos, ctx);
+		}
+		// the word starts after the last character that can't be in one, however many bytes that is
+		let start = line[..pos]
+			.char_indices()
+			.rfind(|(_, c)| !is_symbol_char(*c))
+			.map_or(0, |(i, c)| i + c.len_utf8());
+		let word = &line[start..pos];
+		let candidates = self
+			.names
+			.keys()
+			.filter(|name| name.starts_with(word))
+			.map(|name| Pair {
+				display: name.clone(),
+				replacement: name.clone(),
+			})
+			.collect();
+		Ok((start, candidates))
+	}
+}
+
+impl Hinter for BlisprHelper {
+	type Hint = String;
+
+	// after "(name " show the arguments name expects
+	fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+		if pos < line.len() || !line.ends_with(' ') {
+			return None;
+		}
+		let call = &line[line.rfind('(')? + 1..];
+		let name = call.trim_end();
+		if name.is_empty() || name.contains(char::is_whitespace) {
+			return None;
+		}
+		self.names.get(name)?.args.clone()
+	}
+}
+
+impl Highlighter for BlisprHelper {
+	fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+		if !self.color {
+			if line.contains('\n') {
+				return Cow::Owned(draw_continuations(line));
+			}
+			return Cow::Borrowed(line);
+		}
+		let tokens = tokenize(line);
+		let matching = matching_brackets(&tokens, pos);
+		let mut qexpr_depth = 0;
+		let mut ret = String::with_capacity(line.len() * 2);
+		for (i, (start, end, token)) in tokens.iter().enumerate() {
+			let text = &line[*start..*end];
+			match token {
+				Token::Open('{') => qexpr_depth += 1,
+				Token::Close('}') => qexpr_depth -= 1,
+				_ => {},
+			}
+			let style = match token {
+				_ if matching.is_some_and(|(a, b)| i == a || i == b) => Some(MATCHING_BRACKET),
+				Token::Comment => Some(COMMENT),
+				Token::Char => Some(CHARACTER),
+				Token::Open('{') | Token::Close('}') => Some(QEXPR_BRACE),
+				Token::Word => self.word_style(text, qexpr_depth > 0),
+				Token::Open(_) | Token::Close(_) | Token::Other => None,
+			};
+			match style {
+				Some(style) => {
+					ret.push_str(style);
+					ret.push_str(text);
+					ret.push_str(RESET);
+				},
+				None => ret.push_str(text),
+			}
+		}
+		Cow::Owned(draw_continuations(&ret))
+	}
+
+	fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+		if self.color {
+			Cow::Owned(format!("{HINT}{hint}{RESET}"))
+		} else {
+			Cow::Borrowed(hint)
+		}
+	}
+
+	// moving the cursor can change which brackets match, so always redraw
+	fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+		self.color
+	}
+}
+
+// Enter in the middle of an open form starts a new line instead of running it
+impl Validator for BlisprHelper {
+	fn validate(&self, ctx: &mut ValidationContext) -> Result<ValidationResult> {
+		if needs_more(ctx.input()) {
+			Ok(ValidationResult::Incomplete)
+		} else {
+			Ok(ValidationResult::Valid(None))
+		}
+	}
+}
+
+impl Helper for BlisprHelper {}
+
+// Bound to Enter, so an unfinished form gets a new line with room for the CONTINUATION prompt
+// Anything else falls through to the usual Enter, and the validator
+pub struct ContinueForm;
+
+impl ConditionalEventHandler for ContinueForm {
+	fn handle(
+		&self,
+		_evt: &Event,
+		_n: RepeatCount,
+		_positive: bool,
+		ctx: &EventContext,
+	) -> Option<Cmd> {
+		needs_more(ctx.line())
+			.then(|| Cmd::Insert(1, format!("\n{}", " ".repeat(CONTINUATION.len()))))
+	}
+}
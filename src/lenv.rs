@@ -2,16 +2,22 @@
 // I don't see any reason not to use a HashMap
 // Will be interesting to benchmark later
 use crate::{
+	budget::{Budget, Limits},
+	capability::Capabilities,
+	debugger::Debugger,
 	error::{BlisprResult, Error},
 	eval::{
-		builtin_add, builtin_cons, builtin_div, builtin_eval_stub, builtin_exit, builtin_head,
-		builtin_init, builtin_join, builtin_lambda, builtin_len, builtin_list, builtin_max,
-		builtin_min, builtin_mul, builtin_pow, builtin_printenv_stub, builtin_put_stub,
-		builtin_rem, builtin_sub, builtin_tail,
+		builtin_add, builtin_break_stub, builtin_char_alphabetic, builtin_char_numeric,
+		builtin_char_to_int, builtin_chars_to_str, builtin_cons, builtin_div, builtin_eq,
+		builtin_eval_stub, builtin_exit, builtin_gc, builtin_head, builtin_init,
+		builtin_int_to_char, builtin_is_keyword, builtin_join, builtin_keyword_to_str,
+		builtin_lambda, builtin_len, builtin_list, builtin_max, builtin_min, builtin_mul,
+		builtin_pow, builtin_printenv_stub, builtin_put_stub, builtin_quote, builtin_rem,
+		builtin_str_to_chars, builtin_sub, builtin_tail,
 	},
 	lval::{add, builtin, qexpr, sym, LBuiltin, Lval},
 };
-use std::{collections::HashMap, fmt};
+use std::{collections::HashMap, fmt, rc::Rc};
 
 pub type LEnvLookup = HashMap<String, Box<Lval>>;
 
@@ -19,6 +25,12 @@ pub type LEnvLookup = HashMap<String, Box<Lval>>;
 pub struct Lenv<'a> {
 	lookup: LEnvLookup,
 	pub parent: Option<&'a Lenv<'a>>,
+	// shared with every child env
+	budget: Rc<Budget>,
+	// inherited by every child env
+	capabilities: Capabilities,
+	// inherited by every child env, and handed every call made in them
+	debugger: Option<Rc<Debugger>>,
 }
 
 impl<'a> Lenv<'a> {
@@ -26,6 +38,9 @@ impl<'a> Lenv<'a> {
 		let mut ret = Self {
 			lookup: lookup.unwrap_or_default(),
 			parent,
+			budget: parent.map_or_else(Rc::default, |p| Rc::clone(&p.budget)),
+			capabilities: parent.map_or_else(Capabilities::default, |p| p.capabilities),
+			debugger: parent.and_then(|p| p.debugger.clone()),
 		};
 
 		// Register builtins
@@ -43,12 +58,15 @@ impl<'a> Lenv<'a> {
 		ret.add_builtin("head", builtin_head);
 		ret.add_builtin("init", builtin_init);
 		ret.add_builtin("list", builtin_list);
+		ret.add_builtin("quote", builtin_quote);
 		ret.add_builtin("join", builtin_join);
 		ret.add_builtin("len", builtin_len);
 		ret.add_builtin("tail", builtin_tail);
 
 		// Utility
+		ret.add_builtin("break", builtin_break_stub);
 		ret.add_builtin("exit", builtin_exit);
+		ret.add_builtin("gc", builtin_gc);
 		ret.add_builtin("printenv", builtin_printenv_stub);
 
 		// Arithmetic
@@ -67,9 +85,51 @@ impl<'a> Lenv<'a> {
 		ret.add_builtin("min", builtin_min);
 		ret.add_builtin("max", builtin_max);
 
+		// Comparison
+		ret.add_builtin("==", builtin_eq);
+
+		// Keywords
+		ret.add_builtin("keyword?", builtin_is_keyword);
+		ret.add_builtin("keyword->str", builtin_keyword_to_str);
+
+		// Characters
+		ret.add_builtin("char->int", builtin_char_to_int);
+		ret.add_builtin("int->char", builtin_int_to_char);
+		ret.add_builtin("char-alphabetic?", builtin_char_alphabetic);
+		ret.add_builtin("char-numeric?", builtin_char_numeric);
+		ret.add_builtin("str->chars", builtin_str_to_chars);
+		ret.add_builtin("chars->str", builtin_chars_to_str);
+
 		ret
 	}
 
+	// replace the execution limits for this env and anything evaluated in it
+	pub fn set_limits(&mut self, limits: Limits) {
+		self.budget = Rc::new(Budget::new(limits));
+	}
+
+	pub fn budget(&self) -> Rc<Budget> {
+		Rc::clone(&self.budget)
+	}
+
+	// restrict which builtins can be called from this env and its children
+	pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+		self.capabilities = capabilities;
+	}
+
+	pub fn capabilities(&self) -> Capabilities {
+		self.capabilities
+	}
+
+	// stop in this debugger when asked to, in this env and its children
+	pub fn set_debugger(&mut self, debugger: Option<Rc<Debugger>>) {
+		self.debugger = debugger;
+	}
+
+	pub fn debugger(&self) -> Option<Rc<Debugger>> {
+		self.debugger.clone()
+	}
+
 	// register a function pointer to the global scope
 	fn add_builtin(&mut self, name: &str, func: LBuiltin) {
 		self.put(name.to_string(), builtin(func, name));
@@ -107,6 +167,11 @@ impl<'a> Lenv<'a> {
 		}
 	}
 
+	// every binding in the local env
+	pub fn entries(&self) -> impl Iterator<Item = (&str, &Lval)> {
+		self.lookup.iter().map(|(k, v)| (k.as_str(), &**v))
+	}
+
 	// Returns an Lval containing Symbols with each k,v pair in the local env
 	pub fn list_all(&self) -> BlisprResult {
 		let mut ret = qexpr();
@@ -126,7 +191,7 @@ impl<'a> Lenv<'a> {
 	}
 }
 
-impl<'a> fmt::Display for Lenv<'a> {
+impl fmt::Display for Lenv<'_> {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		let parent_str = if self.parent.is_some() {
 			"Child"
@@ -0,0 +1,340 @@
+// Static checks on source code, without running any of it
+// Works on the pest tree rather than Lvals, so every finding can point at where it is
+use crate::{
+	doc::arity,
+	error::Result,
+	lenv::Lenv,
+	parse::{BlisprParser, Rule},
+	reader::{read_recovering, Diagnostic},
+};
+use pest::{iterators::Pair, Parser};
+use serde_json::{json, Value};
+use std::{collections::HashSet, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+	Error,
+	Warning,
+}
+
+impl fmt::Display for Severity {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Severity::Error => write!(f, "error"),
+			Severity::Warning => write!(f, "warning"),
+		}
+	}
+}
+
+// Each kind of problem the linter looks for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Check {
+	Syntax,
+	UnknownSymbol,
+	WrongArity,
+	UnusedFormal,
+	BadVarargs,
+	DuplicateFormal,
+}
+
+impl Check {
+	// stable names for machine-readable output
+	pub fn name(self) -> &'static str {
+		match self {
+			Check::Syntax => "syntax-error",
+			Check::UnknownSymbol => "unknown-symbol",
+			Check::WrongArity => "wrong-arity",
+			Check::UnusedFormal => "unused-formal",
+			Check::BadVarargs => "bad-varargs",
+			Check::DuplicateFormal => "duplicate-formal",
+		}
+	}
+
+	pub fn severity(self) -> Severity {
+		match self {
+			Check::UnusedFormal => Severity::Warning,
+			_ => Severity::Error,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+	pub check: Check,
+	pub diagnostic: Diagnostic,
+}
+
+impl Lint {
+	fn new(check: Check, pair: &Pair<Rule>, message: String) -> Self {
+		Self {
+			check,
+			diagnostic: Diagnostic {
+				start: pair.line_col(),
+				end: pair.as_span().end_pos().line_col(),
+				message,
+			},
+		}
+	}
+
+	pub fn to_json(&self, file: &str) -> Value {
+		let Diagnostic {
+			start,
+			end,
+			message,
+		} = &self.diagnostic;
+		json!({
+			"file": file,
+			"line": start.0,
+			"column": start.1,
+			"end_line": end.0,
+			"end_column": end.1,
+			"severity": self.check.severity().to_string(),
+			"code": self.check.name(),
+			"message": message,
+		})
+	}
+}
+
+// line:column: severity: message [check]
+impl fmt::Display for Lint {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (line, col) = self.diagnostic.start;
+		write!(
+			f,
+			"{line}:{col}: {}: {} [{}]",
+			self.check.severity(),
+			self.diagnostic.message,
+			self.check.name()
+		)
+	}
+}
+
+// Everything the linter finds in s, in the order it appears
+// A file with syntax errors only gets those - there's no tree to check until they're fixed
+pub fn lint_source(s: &str) -> Result<Vec<Lint>> {
+	let (_, diagnostics) = read_recovering(s.as_bytes())?;
+	if !diagnostics.is_empty() {
+		return Ok(diagnostics
+			.into_iter()
+			.map(|diagnostic| Lint {
+				check: Check::Syntax,
+				diagnostic,
+			})
+			.collect());
+	}
+	let program = BlisprParser::parse(Rule::blispr, s)?.next().unwrap();
+	let mut linter = Linter::new(&program);
+	for form in exprs(&program) {
+		linter.code(&form);
+	}
+	let mut ret = linter.lints;
+	ret.sort_by_key(|lint| lint.diagnostic.start);
+	Ok(ret)
+}
+
+// The expressions inside a pair, skipping anything commented out with #_
+pub fn exprs<'a>(pair: &Pair<'a, Rule>) -> Vec<Pair<'a, Rule>> {
+	pair.clone()
+		.into_inner()
+		.filter(|p| p.as_rule() == Rule::expr)
+		.map(|p| p.into_inner().next().unwrap())
+		.collect()
+}
+
+// The names a def binds, if they're written out as {a b c}
+pub fn def_names<'a>(items: &[Pair<'a, Rule>]) -> Vec<Pair<'a, Rule>> {
+	match items {
+		[head, names, ..] if head.as_str() == "def" && names.as_rule() == Rule::qexpr => {
+			exprs(names)
+				.into_iter()
+				.filter(|p| p.as_rule() == Rule::symbol)
+				.collect()
+		},
+		_ => Vec::new(),
+	}
+}
+
+// Whether name appears anywhere in pair, as code or data
+fn mentions(pair: &Pair<Rule>, name: &str) -> bool {
+	pair.clone()
+		.into_inner()
+		.flatten()
+		.any(|p| p.as_rule() == Rule::symbol && p.as_str() == name)
+}
+
+struct Linter {
+	// builtins and *args*, plus every name defined so far
+	globals: HashSet<String>,
+	// just the names the file has defined so far
+	defs: HashSet<String>,
+	// every name defined anywhere in the file - a lambda body can use one defined after it
+	all_defs: HashSet<String>,
+	// the formals of each lambda we're inside, innermost last
+	scopes: Vec<Vec<String>>,
+	lints: Vec<Lint>,
+}
+
+impl Linter {
+	fn new(program: &Pair<Rule>) -> Self {
+		let mut globals: HashSet<String> = Lenv::new(None, None)
+			.entries()
+			.map(|(k, _)| k.to_string())
+			.collect();
+		globals.insert("*args*".to_string());
+		let all_defs = program
+			.clone()
+			.into_inner()
+			.flatten()
+			.filter(|p| p.as_rule() == Rule::sexpr)
+			.flat_map(|p| def_names(&exprs(&p)))
+			.map(|p| p.as_str().to_string())
+			.collect();
+		Self {
+			globals,
+			defs: HashSet::new(),
+			all_defs,
+			scopes: Vec::new(),
+			lints: Vec::new(),
+		}
+	}
+
+	fn is_local(&self, name: &str) -> bool {
+		self.scopes
+			.iter()
+			.any(|scope| scope.iter().any(|s| s == name))
+	}
+
+	fn symbol(&mut self, pair: &Pair<Rule>) {
+		let name = pair.as_str();
+		// code in a lambda body doesn't run until it's called, so later defs count too
+		let deferred = !self.scopes.is_empty() && self.all_defs.contains(name);
+		if !(self.is_local(name) || self.globals.contains(name) || deferred) {
+			self.lints.push(Lint::new(
+				Check::UnknownSymbol,
+				pair,
+				format!("unknown symbol `{name}`"),
+			));
+		}
+	}
+
+	// something that gets evaluated
+	fn code(&mut self, pair: &Pair<Rule>) {
+		match pair.as_rule() {
+			Rule::symbol => self.symbol(pair),
+			Rule::sexpr => self.call(pair, &exprs(pair)),
+			// qexprs and quoted things are data unless something evaluates them
+			_ => {},
+		}
+	}
+
+	// the cells of an sexpr, or of a qexpr that's about to be evaluated as one
+	fn call(&mut self, pair: &Pair<Rule>, items: &[Pair<Rule>]) {
+		let Some(head) = items.first() else {
+			return;
+		};
+		if head.as_rule() != Rule::symbol {
+			for item in items {
+				self.code(item);
+			}
+			return;
+		}
+		self.symbol(head);
+		let name = head.as_str();
+		// a builtin might have been redefined, or shadowed by a formal - a def after the call
+		// only counts in a lambda body, the same as for unknown symbols
+		let deferred = !self.scopes.is_empty() && self.all_defs.contains(name);
+		if self.is_local(name) || self.defs.contains(name) || deferred {
+			for item in &items[1..] {
+				self.code(item);
+			}
+			return;
+		}
+		let given = items.len() - 1;
+		if let Some(n) = arity(name).filter(|n| *n != given) {
+			let s = if n == 1 { "" } else { "s" };
+			self.lints.push(Lint::new(
+				Check::WrongArity,
+				pair,
+				format!("`{name}` takes {n} argument{s}, but was given {given}"),
+			));
+		}
+		match name {
+			"\\" => self.lambda(items),
+			"def" => {
+				let names = def_names(items);
+				// names worked out at runtime are just more code
+				if let Some(item) = items.get(1).filter(|_| names.is_empty()) {
+					self.code(item);
+				}
+				for item in items.iter().skip(2) {
+					self.code(item);
+				}
+				// they're bound once the values are
+				let names: Vec<String> = names.iter().map(|p| p.as_str().to_string()).collect();
+				self.globals.extend(names.iter().cloned());
+				self.defs.extend(names);
+			},
+			"eval" => {
+				for item in &items[1..] {
+					if item.as_rule() == Rule::qexpr {
+						self.call(item, &exprs(item));
+					} else {
+						self.code(item);
+					}
+				}
+			},
+			"quote" => {},
+			_ => {
+				for item in &items[1..] {
+					self.code(item);
+				}
+			},
+		}
+	}
+
+	// (\ {formals} {body})
+	fn lambda(&mut self, items: &[Pair<Rule>]) {
+		let (Some(formals), Some(body)) = (items.get(1), items.get(2)) else {
+			return;
+		};
+		if formals.as_rule() != Rule::qexpr || body.as_rule() != Rule::qexpr {
+			return;
+		}
+		let formals: Vec<Pair<Rule>> = exprs(formals)
+			.into_iter()
+			.filter(|p| p.as_rule() == Rule::symbol)
+			.collect();
+		let mut names: Vec<String> = Vec::new();
+		for (i, formal) in formals.iter().enumerate() {
+			let name = formal.as_str();
+			if name == "&" {
+				if formals.len() != i + 2 {
+					self.lints.push(Lint::new(
+						Check::BadVarargs,
+						formal,
+						"`&` must be followed by exactly one formal".to_string(),
+					));
+				}
+			} else if names.iter().any(|n| n == name) {
+				self.lints.push(Lint::new(
+					Check::DuplicateFormal,
+					formal,
+					format!("`{name}` is already a formal"),
+				));
+			} else {
+				names.push(name.to_string());
+				// _ marks one that's meant to go unused
+				if !name.starts_with('_') && !mentions(body, name) {
+					self.lints.push(Lint::new(
+						Check::UnusedFormal,
+						formal,
+						format!("`{name}` is never used"),
+					));
+				}
+			}
+		}
+		self.scopes.push(names);
+		self.call(body, &exprs(body));
+		self.scopes.pop();
+	}
+}
@@ -0,0 +1,391 @@
+// A language server for editors, speaking LSP over any reader and writer - stdin and stdout for `blispr lsp`
+// Documents are synced in full on every change, which is plenty for files this size
+use crate::{
+	doc::{describe, signature},
+	error::{Error, Result},
+	lenv::Lenv,
+	lint::{def_names, exprs, lint_source, Severity},
+	parse::{BlisprParser, Rule},
+	pretty::{format_source, WIDTH},
+	reader::is_symbol_char,
+};
+use log::{debug, warn};
+use pest::Parser;
+use serde_json::{json, Value};
+use std::{
+	collections::HashMap,
+	io::{BufRead, Write},
+};
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// LSP's CompletionItemKind
+const FUNCTION: u8 = 3;
+const VARIABLE: u8 = 6;
+
+// Read one message, or None once the input is closed
+// Each is a Content-Length header, a blank line and then that many bytes of JSON
+pub fn read_message(input: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+	let mut length = None;
+	loop {
+		let mut header = String::new();
+		if input.read_line(&mut header)? == 0 {
+			return Ok(None);
+		}
+		let header = header.trim_end();
+		if header.is_empty() {
+			break;
+		}
+		if let Some(n) = header.strip_prefix("Content-Length:") {
+			length = n.trim().parse().ok();
+		}
+	}
+	let length = length.ok_or_else(|| Error::Io("message without a Content-Length".to_string()))?;
+	let mut body = vec![0; length];
+	input.read_exact(&mut body)?;
+	Ok(Some(body))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> Result<()> {
+	let body = message.to_string();
+	write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+	output.flush()?;
+	Ok(())
+}
+
+// A name bound with (def {name} ...), and where
+struct Definition {
+	name: String,
+	start: (usize, usize),
+	end: (usize, usize),
+	// the source of the value it's bound to
+	value: Option<String>,
+}
+
+// Every def in s, at any depth, if it parses
+fn definitions(s: &str) -> Option<Vec<Definition>> {
+	let program = BlisprParser::parse(Rule::blispr, s).ok()?.next()?;
+	let mut ret = Vec::new();
+	for sexpr in program
+		.into_inner()
+		.flatten()
+		.filter(|p| p.as_rule() == Rule::sexpr)
+	{
+		let items = exprs(&sexpr);
+		for (i, name) in def_names(&items).iter().enumerate() {
+			ret.push(Definition {
+				name: name.as_str().to_string(),
+				start: name.line_col(),
+				end: name.as_span().end_pos().line_col(),
+				value: items.get(i + 2).map(|v| v.as_str().to_string()),
+			});
+		}
+	}
+	Some(ret)
+}
+
+struct Document {
+	text: String,
+	// from the last version that parsed, so they don't vanish while something is half typed
+	definitions: Vec<Definition>,
+}
+
+impl Document {
+	fn new(text: String) -> Self {
+		let definitions = definitions(&text).unwrap_or_default();
+		Self { text, definitions }
+	}
+
+	fn update(&mut self, text: String) {
+		if let Some(definitions) = definitions(&text) {
+			self.definitions = definitions;
+		}
+		self.text = text;
+	}
+
+	fn definition(&self, name: &str) -> Option<&Definition> {
+		self.definitions.iter().find(|d| d.name == name)
+	}
+
+	// LSP counts from 0, and counts columns in UTF-16 code units - we count characters from 1
+	fn position(&self, (line, col): (usize, usize)) -> Value {
+		let character: usize = self
+			.text
+			.lines()
+			.nth(line - 1)
+			.map_or(0, |l| l.chars().take(col - 1).map(char::len_utf16).sum());
+		json!({ "line": line - 1, "character": character })
+	}
+
+	// just past the last character, as a position() would take it
+	fn end(&self) -> (usize, usize) {
+		let lines = self.text.lines().count();
+		match self.text.lines().last() {
+			Some(last) if !self.text.ends_with('\n') => (lines, last.chars().count() + 1),
+			// a trailing newline leaves an empty line after it
+			_ => (lines + 1, 1),
+		}
+	}
+
+	fn range(&self, start: (usize, usize), end: (usize, usize)) -> Value {
+		json!({ "start": self.position(start), "end": self.position(end) })
+	}
+
+	// The symbol under an LSP position
+	fn word_at(&self, position: &Value) -> Option<String> {
+		let line = usize::try_from(position["line"].as_u64()?).ok()?;
+		let character = usize::try_from(position["character"].as_u64()?).ok()?;
+		let chars: Vec<char> = self.text.lines().nth(line)?.chars().collect();
+		let mut units = 0;
+		let mut i = 0;
+		while i < chars.len() && units < character {
+			units += chars[i].len_utf16();
+			i += 1;
+		}
+		let start = chars[..i]
+			.iter()
+			.rposition(|c| !is_symbol_char(*c))
+			.map_or(0, |p| p + 1);
+		let end = chars[i..]
+			.iter()
+			.position(|c| !is_symbol_char(*c))
+			.map_or(chars.len(), |p| i + p);
+		(start < end).then(|| chars[start..end].iter().collect())
+	}
+}
+
+struct Server<W> {
+	output: W,
+	documents: HashMap<String, Document>,
+	shut_down: bool,
+}
+
+impl<W: Write> Server<W> {
+	fn send(&mut self, message: &Value) -> Result<()> {
+		write_message(&mut self.output, message)
+	}
+
+	fn error(&mut self, id: &Value, code: i64, message: &str) -> Result<()> {
+		self.send(&json!({
+			"jsonrpc": "2.0",
+			"id": id,
+			"error": { "code": code, "message": message },
+		}))
+	}
+
+	// Deal with one message - false once the client says to exit
+	fn handle(&mut self, message: &Value) -> Result<bool> {
+		let method = message["method"].as_str().unwrap_or_default();
+		let params = &message["params"];
+		debug!("lsp: {method}");
+		// None for notifications, and for requests nobody handles
+		let result = match method {
+			"initialize" => Some(json!({
+				"capabilities": {
+					"textDocumentSync": 1,
+					"hoverProvider": true,
+					"definitionProvider": true,
+					"completionProvider": {},
+					"documentFormattingProvider": true,
+				},
+				"serverInfo": { "name": "blispr", "version": env!("CARGO_PKG_VERSION") },
+			})),
+			"shutdown" => {
+				self.shut_down = true;
+				Some(Value::Null)
+			},
+			"exit" => return Ok(false),
+			"textDocument/didOpen" => {
+				let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+				let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+				self.documents
+					.insert(uri.to_string(), Document::new(text.to_string()));
+				self.publish_diagnostics(uri)?;
+				None
+			},
+			"textDocument/didChange" => {
+				let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+				// full sync, so the last change is the whole document
+				let text = params["contentChanges"]
+					.as_array()
+					.and_then(|changes| changes.last())
+					.and_then(|change| change["text"].as_str());
+				if let (Some(document), Some(text)) = (self.documents.get_mut(uri), text) {
+					document.update(text.to_string());
+					self.publish_diagnostics(uri)?;
+				}
+				None
+			},
+			"textDocument/didClose" => {
+				let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+				self.documents.remove(uri);
+				self.send(&json!({
+					"jsonrpc": "2.0",
+					"method": "textDocument/publishDiagnostics",
+					"params": { "uri": uri, "diagnostics": [] },
+				}))?;
+				None
+			},
+			"textDocument/hover" => Some(self.hover(params)),
+			"textDocument/definition" => Some(self.definition(params)),
+			"textDocument/completion" => Some(self.completion(params)),
+			"textDocument/formatting" => Some(self.formatting(params)),
+			_ => None,
+		};
+		if let Some(id) = message.get("id") {
+			match result {
+				Some(result) => {
+					self.send(&json!({ "jsonrpc": "2.0", "id": id, "result": result }))?;
+				},
+				None => self.error(id, METHOD_NOT_FOUND, &format!("unknown method {method}"))?,
+			}
+		}
+		Ok(true)
+	}
+
+	fn document(&self, params: &Value) -> Option<&Document> {
+		self.documents.get(params["textDocument"]["uri"].as_str()?)
+	}
+
+	// syntax errors and lints, the same as `blispr lint`
+	fn publish_diagnostics(&mut self, uri: &str) -> Result<()> {
+		let Some(document) = self.documents.get(uri) else {
+			return Ok(());
+		};
+		let diagnostics: Vec<Value> = match lint_source(&document.text) {
+			Ok(lints) => lints
+				.iter()
+				.map(|lint| {
+					json!({
+						"range": document.range(lint.diagnostic.start, lint.diagnostic.end),
+						"severity": if lint.check.severity() == Severity::Error { 1 } else { 2 },
+						"code": lint.check.name(),
+						"source": "blispr",
+						"message": lint.diagnostic.message,
+					})
+				})
+				.collect(),
+			// text the reader took but the grammar didn't - report it rather than stop the server
+			Err(err) => {
+				warn!("lsp: couldn't lint {uri}: {err}");
+				vec![json!({
+					"range": document.range((1, 1), (1, 1)),
+					"severity": 1,
+					"source": "blispr",
+					"message": err.to_string(),
+				})]
+			},
+		};
+		self.send(&json!({
+			"jsonrpc": "2.0",
+			"method": "textDocument/publishDiagnostics",
+			"params": { "uri": uri, "diagnostics": diagnostics },
+		}))
+	}
+
+	// a builtin's signature and description, or the value a def binds
+	fn hover(&self, params: &Value) -> Value {
+		let Some(document) = self.document(params) else {
+			return Value::Null;
+		};
+		let Some(name) = document.word_at(&params["position"]) else {
+			return Value::Null;
+		};
+		let contents = if let Some(definition) = document.definition(&name) {
+			let value = definition.value.as_deref().unwrap_or_default();
+			let value =
+				format_source(value, WIDTH).map_or(value.to_string(), |v| v.trim_end().to_string());
+			format!("`{name}` is defined as\n```blispr\n{value}\n```")
+		} else if let Some(args) = signature(&name) {
+			format!(
+				"```blispr\n({name} {args})\n```\n{}",
+				describe(&name).unwrap_or_default()
+			)
+		} else {
+			return Value::Null;
+		};
+		json!({ "contents": { "kind": "markdown", "value": contents } })
+	}
+
+	fn definition(&self, params: &Value) -> Value {
+		let (Some(document), Some(uri)) = (
+			self.document(params),
+			params["textDocument"]["uri"].as_str(),
+		) else {
+			return Value::Null;
+		};
+		match document
+			.word_at(&params["position"])
+			.and_then(|name| document.definition(&name))
+		{
+			Some(definition) => json!({
+				"uri": uri,
+				"range": document.range(definition.start, definition.end),
+			}),
+			None => Value::Null,
+		}
+	}
+
+	// every builtin, and everything the document defines
+	fn completion(&self, params: &Value) -> Value {
+		let mut items: Vec<Value> = Lenv::new(None, None)
+			.entries()
+			.map(|(name, _)| {
+				json!({
+					"label": name,
+					"kind": FUNCTION,
+					"detail": format!("({name} {})", signature(name).unwrap_or_default()),
+					"documentation": describe(name).unwrap_or_default(),
+				})
+			})
+			.collect();
+		if let Some(document) = self.document(params) {
+			items.extend(document.definitions.iter().map(|definition| {
+				json!({
+					"label": definition.name,
+					"kind": VARIABLE,
+					"detail": definition.value,
+				})
+			}));
+		}
+		items.sort_by(|a, b| a["label"].as_str().cmp(&b["label"].as_str()));
+		Value::Array(items)
+	}
+
+	// one edit replacing the whole document, or nothing if it doesn't parse
+	fn formatting(&self, params: &Value) -> Value {
+		let Some(document) = self.document(params) else {
+			return Value::Null;
+		};
+		match format_source(&document.text, WIDTH) {
+			Ok(formatted) => json!([{
+				"range": document.range((1, 1), document.end()),
+				"newText": formatted,
+			}]),
+			Err(_) => Value::Null,
+		}
+	}
+}
+
+// Answer requests until the client sends exit or closes the input
+// The exit status is 0 if it asked for a shutdown first, as the spec says
+pub fn serve(mut input: impl BufRead, output: impl Write) -> Result<i32> {
+	let mut server = Server {
+		output,
+		documents: HashMap::new(),
+		shut_down: false,
+	};
+	while let Some(body) = read_message(&mut input)? {
+		match serde_json::from_slice::<Value>(&body) {
+			Ok(message) => {
+				if !server.handle(&message)? {
+					break;
+				}
+			},
+			Err(e) => server.error(&Value::Null, PARSE_ERROR, &e.to_string())?,
+		}
+	}
+	Ok(i32::from(!server.shut_down))
+}
@@ -1,27 +1,95 @@
-use crate::error::{BlisprResult, Error, Result};
-use std::{collections::HashMap, fmt};
+use crate::{
+	compile::CompiledBody,
+	error::{BlisprResult, Error, Result},
+	gc::{Bindings, Gc},
+};
+use std::{
+	cell::RefCell,
+	collections::HashSet,
+	fmt,
+	ops::{Deref, DerefMut},
+	rc::Rc,
+};
 
 // The recursive types hold their children in one of these bad boys
+// It's a Vec that also remembers the source line the list was read from, for line breakpoints
 // TODO Should this be a VecDeque or a LinkedList instead?
-type LvalChildren = Vec<Box<Lval>>;
+#[derive(Clone, Default)]
+pub struct LvalChildren {
+	#[allow(clippy::vec_box)]
+	cells: Vec<Box<Lval>>,
+	pub line: Option<usize>,
+}
+
+impl Deref for LvalChildren {
+	type Target = Vec<Box<Lval>>;
+	fn deref(&self) -> &Self::Target {
+		&self.cells
+	}
+}
+
+impl DerefMut for LvalChildren {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.cells
+	}
+}
+
+impl From<Vec<Box<Lval>>> for LvalChildren {
+	fn from(cells: Vec<Box<Lval>>) -> Self {
+		Self { cells, line: None }
+	}
+}
+
+impl IntoIterator for LvalChildren {
+	type Item = Box<Lval>;
+	type IntoIter = std::vec::IntoIter<Box<Lval>>;
+	fn into_iter(self) -> Self::IntoIter {
+		self.cells.into_iter()
+	}
+}
+
+impl<'a> IntoIterator for &'a LvalChildren {
+	type Item = &'a Box<Lval>;
+	type IntoIter = std::slice::Iter<'a, Box<Lval>>;
+	fn into_iter(self) -> Self::IntoIter {
+		self.cells.iter()
+	}
+}
+
+// where a list came from doesn't change what it is
+impl PartialEq for LvalChildren {
+	fn eq(&self, other: &Self) -> bool {
+		self.cells == other.cells
+	}
+}
+
+impl fmt::Debug for LvalChildren {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		self.cells.fmt(f)
+	}
+}
 pub type LBuiltin = fn(&mut Lval) -> BlisprResult;
 
 // There are two types of function - builtin and lambda
 #[derive(Clone)]
 pub enum Func {
-	Builtin(String, LBuiltin), // (name, function pointer)
-	Lambda(HashMap<String, Box<Lval>>, Box<Lval>, Box<Lval>), // (environment(?), formals, body), both should be Qexpr // TODO these should both be Rc<T>
+	Builtin(String, LBuiltin),                      // (name, function pointer)
+	Lambda(Gc, Box<Lval>, Box<Lval>, CompiledBody), // (environment(?), formals, body, the VM's code for body), both should be Qexpr // TODO these should both be Rc<T>
 }
 
 // The book has a pointer to an Lenv in the Lambda
 // I instead just store a plain old hashmap of any extras
 // it's then applied in lval_call
+// The hashmap lives on the heap in gc.rs, shared by every copy of the lambda - see there for how it's freed
 
 // The main type - all possible Blispr values
 #[derive(Debug, Clone, PartialEq)]
 pub enum Lval {
 	Blispr(LvalChildren),
+	Char(char),
 	Fun(Func),
+	// the name without its colon - interned, so every :name shares one allocation
+	Keyword(Rc<str>),
 	Num(i64),
 	Sym(String),
 	Sexpr(LvalChildren),
@@ -37,10 +105,41 @@ impl Lval {
 	}
 	pub fn as_string(&self) -> Result<String> {
 		match self {
-			Lval::Sym(s) => Ok(s.to_string()),
+			Lval::Sym(s) => Ok(s.clone()),
 			_ => Err(Error::WrongType("symbol".to_string(), format!("{self}"))),
 		}
 	}
+	// the name :type shows for this kind of value
+	pub fn type_name(&self) -> &'static str {
+		match self {
+			Lval::Blispr(_) => "program",
+			Lval::Char(_) => "character",
+			Lval::Fun(Func::Builtin(..)) => "builtin",
+			Lval::Fun(Func::Lambda(..)) => "lambda",
+			Lval::Keyword(_) => "keyword",
+			Lval::Num(_) => "number",
+			Lval::Sym(_) => "symbol",
+			Lval::Sexpr(_) => "s-expression",
+			Lval::Qexpr(_) => "q-expression",
+		}
+	}
+	// how many cells make up this value, counting every nested child
+	pub fn cells(&self) -> usize {
+		match self {
+			Lval::Sexpr(children) | Lval::Qexpr(children) | Lval::Blispr(children) => {
+				1 + children.iter().map(|c| c.cells()).sum::<usize>()
+			},
+			Lval::Fun(Func::Lambda(env, formals, body, _)) => {
+				let captured: usize = env.bindings().borrow().values().map(|v| v.cells()).sum();
+				1 + formals.cells() + body.cells() + captured
+			},
+			Lval::Char(_)
+			| Lval::Fun(Func::Builtin(..))
+			| Lval::Keyword(_)
+			| Lval::Num(_)
+			| Lval::Sym(_) => 1,
+		}
+	}
 	pub fn len(&self) -> Result<usize> {
 		match *self {
 			Lval::Sexpr(ref children) | Lval::Qexpr(ref children) | Lval::Blispr(ref children) => {
@@ -55,7 +154,7 @@ impl fmt::Debug for Func {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
 			Func::Builtin(name, _) => write!(f, "Builtin({name})"),
-			Func::Lambda(env, formals, body) => {
+			Func::Lambda(env, formals, body, _) => {
 				write!(f, "Lambda({{{env:?}}},{{{formals}}},{{{body}}})")
 			},
 		}
@@ -69,8 +168,9 @@ impl PartialEq for Func {
 				Func::Builtin(other_name, _) => name == other_name,
 				Func::Lambda(..) => false,
 			},
-			Func::Lambda(env, formals, body) => match other {
-				Func::Lambda(other_env, other_f, other_b) => {
+			// the compiled code always follows from the body
+			Func::Lambda(env, formals, body, _) => match other {
+				Func::Lambda(other_env, other_f, other_b, _) => {
 					formals == other_f && body == other_b && env == other_env
 				},
 				Func::Builtin(..) => false,
@@ -83,27 +183,54 @@ impl fmt::Display for Lval {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
 			Lval::Blispr(_cells) => write!(f, "<toplevel>"),
+			Lval::Char(c) => match char_name(*c) {
+				Some(name) => write!(f, "#\\{name}"),
+				None => write!(f, "#\\{c}"),
+			},
 			Lval::Fun(lf) => match lf {
 				Func::Builtin(name, _) => write!(f, "<builtin: {name}>"),
-				Func::Lambda(_, formals, body) => write!(f, "(\\ {formals} {body})"),
+				Func::Lambda(_, formals, body, _) => write!(f, "(\\ {formals} {body})"),
 			},
+			Lval::Keyword(k) => write!(f, ":{k}"),
 			Lval::Num(n) => write!(f, "{n}"),
 			Lval::Sym(s) => write!(f, "{s}"),
-			Lval::Sexpr(cell) => write!(f, "({})", lval_expr_print(cell)),
+			Lval::Sexpr(cell) => match quoted(cell) {
+				Some(x) => write!(f, "'{x}"),
+				None => write!(f, "({})", lval_expr_print(cell)),
+			},
 			Lval::Qexpr(cell) => write!(f, "{{{}}}", lval_expr_print(cell)),
 		}
 	}
 }
 
-fn lval_expr_print(cell: &[Box<Lval>]) -> String {
-	let mut ret = String::new();
-	for i in 0..cell.len() {
-		ret.push_str(&format!("{}", cell[i]));
-		if i < cell.len() - 1 {
-			ret.push(' ');
-		}
+// Characters written by name rather than as themselves
+const CHAR_NAMES: &[(char, &str)] = &[(' ', "space"), ('\n', "newline"), ('\t', "tab")];
+
+pub fn char_name(c: char) -> Option<&'static str> {
+	CHAR_NAMES
+		.iter()
+		.find(|(ch, _)| *ch == c)
+		.map(|(_, name)| *name)
+}
+
+pub fn named_char(name: &str) -> Option<char> {
+	CHAR_NAMES.iter().find(|(_, n)| *n == name).map(|(c, _)| *c)
+}
+
+// The x in (quote x), which is what 'x reads as
+// Both evaluators hand x back untouched rather than evaluating it
+pub fn quoted(cells: &[Box<Lval>]) -> Option<&Lval> {
+	match cells {
+		[head, x] if matches!(**head, Lval::Sym(ref s) if s == "quote") => Some(x),
+		_ => None,
 	}
-	ret
+}
+
+fn lval_expr_print(cell: &[Box<Lval>]) -> String {
+	cell.iter()
+		.map(ToString::to_string)
+		.collect::<Vec<String>>()
+		.join(" ")
 }
 
 // Constructors
@@ -111,15 +238,41 @@ fn lval_expr_print(cell: &[Box<Lval>]) -> String {
 // The recursive types start empty
 
 pub fn blispr() -> Box<Lval> {
-	Box::new(Lval::Blispr(Vec::new()))
+	Box::new(Lval::Blispr(LvalChildren::default()))
 }
 
 pub fn builtin(f: LBuiltin, name: &str) -> Box<Lval> {
 	Box::new(Lval::Fun(Func::Builtin(name.to_string(), f)))
 }
 
-pub fn lambda(env: HashMap<String, Box<Lval>>, formals: Box<Lval>, body: Box<Lval>) -> Box<Lval> {
-	Box::new(Lval::Fun(Func::Lambda(env, formals, body)))
+pub fn lambda(env: Bindings, formals: Box<Lval>, body: Box<Lval>) -> Box<Lval> {
+	Box::new(Lval::Fun(Func::Lambda(
+		Gc::new(env),
+		formals,
+		body,
+		CompiledBody::default(),
+	)))
+}
+
+thread_local! {
+	static KEYWORDS: RefCell<HashSet<Rc<str>>> = RefCell::new(HashSet::new());
+}
+
+pub fn character(c: char) -> Box<Lval> {
+	Box::new(Lval::Char(c))
+}
+
+pub fn keyword(name: &str) -> Box<Lval> {
+	let interned = KEYWORDS.with(|keywords| {
+		let mut keywords = keywords.borrow_mut();
+		if let Some(k) = keywords.get(name) {
+			return Rc::clone(k);
+		}
+		let k: Rc<str> = Rc::from(name);
+		keywords.insert(Rc::clone(&k));
+		k
+	});
+	Box::new(Lval::Keyword(interned))
 }
 
 pub fn num(n: i64) -> Box<Lval> {
@@ -131,11 +284,19 @@ pub fn sym(s: &str) -> Box<Lval> {
 }
 
 pub fn sexpr() -> Box<Lval> {
-	Box::new(Lval::Sexpr(Vec::new()))
+	Box::new(Lval::Sexpr(LvalChildren::default()))
 }
 
 pub fn qexpr() -> Box<Lval> {
-	Box::new(Lval::Qexpr(Vec::new()))
+	Box::new(Lval::Qexpr(LvalChildren::default()))
+}
+
+// Note the line a list was read from, for line breakpoints
+pub fn at_line(mut v: Box<Lval>, line: usize) -> Box<Lval> {
+	if let Lval::Sexpr(ref mut children) | Lval::Qexpr(ref mut children) = *v {
+		children.line = Some(line);
+	}
+	v
 }
 
 // Manipulating children
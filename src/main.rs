@@ -1,36 +1,196 @@
 #![warn(clippy::pedantic)]
+// Lvals are recursive, so they live behind a Box everywhere
+#![allow(clippy::unnecessary_box_returns)]
 
-use clap::Parser;
+use crate::{capability::Capability, config::EditMode};
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use std::{path::PathBuf, process::exit};
 
 #[macro_use]
 extern crate pest_derive;
 
+mod budget;
+mod capability;
+mod compile;
+mod config;
+mod debugger;
+mod doc;
 mod error;
 mod eval;
+mod gc;
+mod helper;
 mod lenv;
+mod lint;
+mod lsp;
 mod lval;
 mod parse;
+mod pretty;
+mod reader;
 mod run;
+mod session;
+mod vm;
 
 #[cfg(test)]
 mod test;
 
 use crate::run::run;
 
+// Which evaluator runs the parsed program
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backend {
+	/// walk the Lval tree directly
+	#[default]
+	Tree,
+	/// compile to bytecode and run it on the stack VM
+	Vm,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+	/// reformat source files in place
+	Fmt(FmtOpt),
+	/// check source files for mistakes without running them
+	Lint(LintOpt),
+	/// run a language server for editors, over stdin and stdout
+	Lsp,
+}
+
+#[derive(clap::Args)]
+pub struct FmtOpt {
+	/// don't write anything, just list the files that aren't formatted and exit with 1 if there are any
+	#[clap(long)]
+	check: bool,
+	/// line width to fit code into
+	#[clap(long, default_value_t = pretty::WIDTH)]
+	width: usize,
+	/// files to format, or - to format stdin to stdout (the default)
+	files: Vec<PathBuf>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LintFormat {
+	/// `file:line:column: severity: message [check]`
+	#[default]
+	Human,
+	/// one JSON object per line
+	Json,
+}
+
+#[derive(clap::Args)]
+pub struct LintOpt {
+	/// how to print what's found
+	#[clap(long, value_enum, default_value_t)]
+	format: LintFormat,
+	/// files to check, or - for stdin (the default)
+	files: Vec<PathBuf>,
+}
+
+// every switch is its own bool, which is what clap wants
+#[allow(clippy::struct_excessive_bools)]
+// a subcommand has to come first, so `blispr -e expr fmt` still passes fmt to the program
 #[derive(clap::Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct Opt {
+	#[clap(subcommand)]
+	command: Option<Command>,
+	/// evaluation backend [default: tree]
+	#[clap(short, long, value_enum)]
+	backend: Option<Backend>,
 	/// debug mode
 	#[clap(short, long)]
 	debug: bool,
-	/// input file
+	/// run the program under the step debugger, stopping at the first call unless --break says where
+	#[clap(long)]
+	debugger: bool,
+	/// stop in the debugger before calls to this function, or calls on this line (repeatable)
+	#[clap(long = "break", value_name = "NAME|LINE")]
+	breakpoints: Vec<String>,
+	/// deny builtins that need this capability (repeatable)
+	#[clap(long, value_enum)]
+	deny: Vec<Capability>,
+	/// input file, or - for stdin
 	#[clap(short, long)]
 	input: Option<PathBuf>,
+	/// print the value of each program run from the command line (the default)
+	#[clap(long, overrides_with = "quiet")]
+	print_result: bool,
+	/// only print what the program itself outputs, and errors
+	#[clap(short, long, overrides_with = "print_result")]
+	quiet: bool,
+	/// print each top-level form and its value as it runs, like a REPL transcript
+	#[clap(long, conflicts_with = "quiet")]
+	echo: bool,
+	/// report errors and carry on with the next form, exiting with the first error's status
+	#[clap(long)]
+	continue_on_error: bool,
+	/// evaluate an expression and print the result (repeatable)
+	#[clap(short, long = "eval", value_name = "EXPR")]
+	eval: Vec<String>,
+	/// REPL history file
+	#[clap(long)]
+	history: Option<PathBuf>,
+	/// number of REPL history entries to keep
+	#[clap(long)]
+	history_size: Option<usize>,
+	/// REPL prompt
+	#[clap(long)]
+	prompt: Option<String>,
+	/// REPL key bindings
+	#[clap(long, value_enum)]
+	edit_mode: Option<EditMode>,
+	/// don't print the REPL startup banner
+	#[clap(long)]
+	no_banner: bool,
+	/// maximum evaluation steps per program
+	#[clap(long)]
+	max_steps: Option<u64>,
+	/// maximum depth of nested function calls
+	#[clap(long)]
+	max_depth: Option<usize>,
+	/// maximum cells returned by function calls per program, counted again at each call a value passes back through
+	#[clap(long)]
+	max_cells: Option<usize>,
+	/// wall-clock limit per program, in milliseconds
+	#[clap(long)]
+	timeout: Option<u64>,
+	/// lambda environments allocated between automatic garbage collections, or 0 to only collect on (gc ())
+	#[clap(long, value_name = "N")]
+	gc_threshold: Option<usize>,
+	/// script to run (- for stdin) followed by its arguments, which are bound to *args*
+	/// with --input or --eval every argument goes to *args*
+	#[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+	args: Vec<String>,
+}
+
+impl Opt {
+	fn debugging(&self) -> bool {
+		self.debugger || !self.breakpoints.is_empty()
+	}
+
+	// the debugger works on the tree-walker, so check() won't let it have the VM
+	fn backend(&self) -> Backend {
+		self.backend.unwrap_or_default()
+	}
+
+	// flags clap can't check by itself
+	fn check(self) -> Result<Self, clap::Error> {
+		if self.debugging() && self.backend == Some(Backend::Vm) {
+			return Err(Self::command().error(
+				ErrorKind::ArgumentConflict,
+				"--debugger and --break need --backend tree",
+			));
+		}
+		Ok(self)
+	}
 }
 
 fn main() {
-	if let Err(e) = run(Opt::parse()) {
-		eprintln!("Error: {e}");
-		exit(1);
+	let opt = Opt::parse().check().unwrap_or_else(|e| e.exit());
+	match run(&opt) {
+		Ok(status) => exit(status),
+		Err(e) => {
+			eprintln!("Error: {e}");
+			exit(e.exit_code());
+		},
 	}
 }
@@ -1,11 +1,18 @@
 use crate::{
-	error::{BlisprResult, Result},
+	error::{BlisprResult, Error, Result},
 	eval::lval_eval,
 	lenv::Lenv,
-	lval::{add, blispr, num, qexpr, sexpr, sym, Lval},
+	lval::{add, at_line, blispr, character, keyword, named_char, num, qexpr, sexpr, sym, Lval},
+	reader::Reader,
+	vm::vm_eval,
+	Backend,
 };
 use log::debug;
-use pest::{iterators::Pair, Parser};
+use pest::{
+	error::{Error as PestError, ErrorVariant},
+	iterators::Pair,
+	Parser,
+};
 
 #[cfg(debug_assertions)]
 const _GRAMMAR: &str = include_str!("blispr.pest");
@@ -22,10 +29,29 @@ fn is_bracket_or_eoi(parsed: &Pair<Rule>) -> bool {
 	c == "(" || c == ")" || c == "{" || c == "}"
 }
 
+// The value of an integer literal like -1_000 or 0xff, if it is one and fits in an i64
+pub fn int_value(literal: &str) -> Option<i64> {
+	let (negative, unsigned) = match literal.strip_prefix('-') {
+		Some(rest) => (true, rest),
+		None => (false, literal.strip_prefix('+').unwrap_or(literal)),
+	};
+	let (radix, digits) = match unsigned.get(..2) {
+		Some("0x") => (16, &unsigned[2..]),
+		Some("0o") => (8, &unsigned[2..]),
+		Some("0b") => (2, &unsigned[2..]),
+		_ if unsigned.starts_with(|c: char| c.is_ascii_digit()) => (10, unsigned),
+		_ => return None,
+	};
+	let digits: String = digits.chars().filter(|c| *c != '_').collect();
+	// i128 so that i64::MIN, whose magnitude doesn't fit in an i64, still works
+	let magnitude = i128::from_str_radix(&digits, radix).ok()?;
+	i64::try_from(if negative { -magnitude } else { magnitude }).ok()
+}
+
 // Read a rule with children into the given containing Lval
 fn read_to_lval(v: &mut Lval, parsed: Pair<Rule>) -> Result<()> {
 	for child in parsed.into_inner() {
-		if is_bracket_or_eoi(&child) {
+		if is_bracket_or_eoi(&child) || matches!(child.as_rule(), Rule::shebang | Rule::discard) {
 			continue;
 		}
 		add(v, &*lval_read(child)?)?;
@@ -42,25 +68,129 @@ fn lval_read(parsed: Pair<Rule>) -> BlisprResult {
 		},
 		Rule::expr => lval_read(parsed.into_inner().next().unwrap()),
 		Rule::sexpr => {
-			let mut ret = sexpr();
+			let mut ret = at_line(sexpr(), parsed.line_col().0);
 			read_to_lval(&mut ret, parsed)?;
 			Ok(ret)
 		},
 		Rule::qexpr => {
-			let mut ret = qexpr();
+			let mut ret = at_line(qexpr(), parsed.line_col().0);
 			read_to_lval(&mut ret, parsed)?;
 			Ok(ret)
 		},
-		Rule::num => Ok(num(parsed.as_str().parse::<i64>()?)),
+		Rule::quoted => {
+			let mut ret = sexpr();
+			add(&mut ret, &sym("quote"))?;
+			add(&mut ret, &*lval_read(parsed.into_inner().next().unwrap())?)?;
+			Ok(ret)
+		},
+		// the grammar only lets valid digits through, so failing here means it's too big
+		Rule::num => match int_value(parsed.as_str()) {
+			Some(n) => Ok(num(n)),
+			None => Err(PestError::new_from_span(
+				ErrorVariant::<Rule>::CustomError {
+					message: "integer literal out of range".to_string(),
+				},
+				parsed.as_span(),
+			)
+			.into()),
+		},
 		Rule::symbol => Ok(sym(parsed.as_str())),
+		Rule::keyword => Ok(keyword(&parsed.as_str()[1..])),
+		Rule::character => {
+			let text = &parsed.as_str()[2..];
+			let c = named_char(text).or_else(|| text.chars().next()).unwrap();
+			Ok(character(c))
+		},
 		_ => unreachable!(), // COMMENT/WHITESPACE etc
 	}
 }
 
-pub fn eval_str(e: &mut Lenv, s: &str) -> BlisprResult {
+// If s starts with a comment, its length and whether it was closed
+// Line comments stop before the newline, and block comments nest
+pub fn comment_len(s: &str) -> Option<(usize, bool)> {
+	if s.starts_with(';') {
+		return Some((s.find('\n').unwrap_or(s.len()), true));
+	}
+	if !s.starts_with("/*") {
+		return None;
+	}
+	let mut depth = 0;
+	let mut i = 0;
+	while i < s.len() {
+		if s[i..].starts_with("/*") {
+			depth += 1;
+			i += 2;
+		} else if s[i..].starts_with("*/") {
+			depth -= 1;
+			i += 2;
+			if depth == 0 {
+				return Some((i, true));
+			}
+		} else {
+			i += s[i..].chars().next().map_or(1, char::len_utf8);
+		}
+	}
+	Some((s.len(), false))
+}
+
+// True if s stops partway through a form - an unclosed bracket or comment
+// Any other mistake, like too many closing brackets, is just a parse error that more input can't fix
+pub fn is_incomplete(s: &str) -> bool {
+	Reader::new(s.as_bytes()).any(|form| matches!(form, Err(Error::Incomplete(..))))
+}
+
+// Parse s into an Lval::Blispr holding each top-level form
+pub fn read_str(s: &str) -> BlisprResult {
+	let mut ret = blispr();
+	for form in read_forms(s)? {
+		add(&mut ret, &form.value)?;
+	}
+	debug!("Parsed: {:?}", *ret);
+	Ok(ret)
+}
+
+// One top-level form of a program, and where it starts in the source
+pub struct Form {
+	pub value: Box<Lval>,
+	pub source: String,
+	pub line: usize,
+	pub col: usize,
+}
+
+// Parse s into its top-level forms, so they can be evaluated one at a time
+pub fn read_forms(s: &str) -> Result<Vec<Form>> {
 	let parsed = BlisprParser::parse(Rule::blispr, s)?.next().unwrap();
 	debug!("{}", parsed);
-	let mut lval_ptr = lval_read(parsed)?;
-	debug!("Parsed: {:?}", *lval_ptr);
-	lval_eval(e, &mut lval_ptr)
+	parsed
+		.into_inner()
+		.filter(|pair| pair.as_rule() == Rule::expr)
+		.map(|pair| {
+			let (line, col) = pair.line_col();
+			Ok(Form {
+				source: pair.as_str().to_string(),
+				line,
+				col,
+				value: lval_read(pair)?,
+			})
+		})
+		.collect()
+}
+
+// Parse s and evaluate it with the chosen backend
+pub fn eval_str(e: &mut Lenv, s: &str, backend: Backend) -> BlisprResult {
+	eval_program(e, read_str(s)?, backend)
+}
+
+// Evaluate an already parsed program, with a fresh execution budget
+pub fn eval_program(e: &mut Lenv, mut program: Box<Lval>, backend: Backend) -> BlisprResult {
+	e.budget().reset();
+	eval_form(e, &mut program, backend)
+}
+
+// Evaluate part of a program - the budget carries on from what came before
+pub fn eval_form(e: &mut Lenv, v: &mut Lval, backend: Backend) -> BlisprResult {
+	match backend {
+		Backend::Tree => lval_eval(e, v),
+		Backend::Vm => vm_eval(e, v),
+	}
 }
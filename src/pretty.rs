@@ -0,0 +1,318 @@
+// A width-aware pretty-printer, used by `blispr fmt` and for showing definitions
+// Source is read into a concrete syntax tree first, so comments and blank lines survive formatting
+use crate::{
+	error::{Error, Result},
+	lval::{quoted, Func, Lval},
+	parse::{comment_len, int_value, BlisprParser, Rule},
+	reader::read_recovering,
+};
+use pest::{iterators::Pair, Parser};
+
+pub const WIDTH: usize = 80;
+
+// Forms that keep this many arguments on the first line, with the rest indented as a body
+const BODY_FORMS: &[(&str, usize)] = &[("\\", 1), ("def", 1), ("if", 1), ("let", 1)];
+
+// the body of one of those is indented this much past the opening bracket
+const BODY_INDENT: usize = 2;
+
+// One piece of source, as written
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+	// a number, symbol, keyword or character
+	Atom(String),
+	// ( or { and what's inside
+	List(char, Vec<Node>),
+	// ' or #_ in front of something
+	Prefix(&'static str, Box<Node>),
+	// trailing comments were on the same line as the node before them
+	Comment { text: String, trailing: bool },
+	// one or more blank lines between two nodes
+	Blank,
+}
+
+// Read the top-level nodes of s, comments and all
+// The grammar skips comments, so they're picked back out of the text between the nodes it returns
+pub fn read_cst(s: &str) -> Result<Vec<Node>> {
+	let parsed = BlisprParser::parse(Rule::blispr, s)?.next().unwrap();
+	Ok(cst_nodes(parsed, 0, s.len()))
+}
+
+// The comments in the text between two nodes, with a Blank before any that follow a blank line
+// Returns how many newlines come after the last comment
+// at_start is true if there's nothing before gap for a comment to trail after
+fn trivia(gap: &str, at_start: bool, out: &mut Vec<Node>) -> usize {
+	let mut newlines = 0;
+	let mut at_start = at_start;
+	let mut i = 0;
+	while i < gap.len() {
+		if let Some((len, _)) = comment_len(&gap[i..]) {
+			if newlines > 1 && !out.is_empty() {
+				out.push(Node::Blank);
+			}
+			out.push(Node::Comment {
+				text: gap[i..i + len].trim_end().to_string(),
+				trailing: newlines == 0 && !at_start,
+			});
+			newlines = 0;
+			at_start = false;
+			i += len;
+		} else {
+			let c = gap[i..].chars().next().unwrap();
+			if c == '\n' {
+				newlines += 1;
+			}
+			i += c.len_utf8();
+		}
+	}
+	newlines
+}
+
+// The children of a pair that lie between start and end, and the comments and blank lines around them
+fn cst_nodes(parsed: Pair<Rule>, start: usize, end: usize) -> Vec<Node> {
+	let source = parsed.get_input();
+	let mut ret = Vec::new();
+	let mut prev_end = start;
+	for pair in parsed.into_inner() {
+		let span = pair.as_span();
+		// only the top level has no bracket for a comment to trail after
+		let newlines = trivia(
+			&source[prev_end..span.start()],
+			start == 0 && prev_end == 0,
+			&mut ret,
+		);
+		prev_end = span.end();
+		if pair.as_rule() == Rule::EOI {
+			continue;
+		}
+		if newlines > 1 && !ret.is_empty() {
+			ret.push(Node::Blank);
+		}
+		let node = match pair.as_rule() {
+			Rule::shebang => Node::Comment {
+				text: pair.as_str().trim_end().to_string(),
+				trailing: false,
+			},
+			Rule::discard => Node::Prefix("#_", Box::new(prefixed(pair, 2, &mut ret))),
+			Rule::expr => cst_node(pair.into_inner().next().unwrap(), &mut ret),
+			_ => unreachable!(), // only the rules that can appear between forms
+		};
+		ret.push(node);
+	}
+	trivia(
+		&source[prev_end..end],
+		ret.is_empty() && start == 0,
+		&mut ret,
+	);
+	ret
+}
+
+// The node after a prefix that's len bytes long
+// Comments between the two are moved in front of it, into before
+fn prefixed(parsed: Pair<Rule>, len: usize, before: &mut Vec<Node>) -> Node {
+	let source = parsed.get_input();
+	let gap_start = parsed.as_span().start() + len;
+	let x = parsed.into_inner().next().unwrap();
+	trivia(&source[gap_start..x.as_span().start()], true, before);
+	cst_node(x.into_inner().next().unwrap(), before)
+}
+
+fn cst_node(parsed: Pair<Rule>, before: &mut Vec<Node>) -> Node {
+	match parsed.as_rule() {
+		Rule::sexpr | Rule::qexpr => {
+			let open = parsed.as_str().chars().next().unwrap();
+			let span = parsed.as_span();
+			Node::List(open, cst_nodes(parsed, span.start() + 1, span.end() - 1))
+		},
+		Rule::quoted => Node::Prefix("'", Box::new(prefixed(parsed, 1, before))),
+		_ => Node::Atom(parsed.as_str().to_string()),
+	}
+}
+
+// The same thing as a node, for printing values rather than source
+fn lval_node(v: &Lval) -> Node {
+	let list =
+		|open, cells: &[Box<Lval>]| Node::List(open, cells.iter().map(|c| lval_node(c)).collect());
+	match v {
+		Lval::Sexpr(cells) => match quoted(cells) {
+			Some(x) => Node::Prefix("'", Box::new(lval_node(x))),
+			None => list('(', cells),
+		},
+		Lval::Qexpr(cells) => list('{', cells),
+		Lval::Fun(Func::Lambda(_, formals, body, _)) => Node::List(
+			'(',
+			vec![
+				Node::Atom("\\".to_string()),
+				lval_node(formals),
+				lval_node(body),
+			],
+		),
+		_ => Node::Atom(v.to_string()),
+	}
+}
+
+// The node on one line, if it can go on one - comments and blank lines can't
+fn flat(node: &Node) -> Option<String> {
+	match node {
+		Node::Atom(s) => Some(s.clone()),
+		Node::Prefix(p, x) => Some(format!("{p}{}", flat(x)?)),
+		Node::List(open, nodes) => {
+			let inner = nodes.iter().map(flat).collect::<Option<Vec<String>>>()?;
+			let close = if *open == '(' { ')' } else { '}' };
+			Some(format!("{open}{}{close}", inner.join(" ")))
+		},
+		Node::Comment { .. } | Node::Blank => None,
+	}
+}
+
+// a symbol rather than a number, keyword or character
+fn is_symbol(atom: &str) -> bool {
+	int_value(atom).is_none() && !atom.starts_with([':', '#'])
+}
+
+fn is_line_comment(node: &Node) -> bool {
+	matches!(node, Node::Comment { text, .. } if text.starts_with(';') || text.starts_with("#!"))
+}
+
+struct Printer {
+	out: String,
+	// column the next character goes in, from 0
+	col: usize,
+	width: usize,
+}
+
+impl Printer {
+	fn push(&mut self, s: &str) {
+		self.out.push_str(s);
+		self.col = match s.rfind('\n') {
+			Some(i) => s[i + 1..].chars().count(),
+			None => self.col + s.chars().count(),
+		};
+	}
+
+	fn newline(&mut self, indent: usize, blank: bool) {
+		if blank {
+			self.out.push('\n');
+		}
+		self.out.push('\n');
+		self.out.push_str(&" ".repeat(indent));
+		self.col = indent;
+	}
+
+	fn node(&mut self, node: &Node) {
+		if let Some(s) = flat(node).filter(|s| self.col + s.chars().count() <= self.width) {
+			return self.push(&s);
+		}
+		match node {
+			Node::Atom(s) | Node::Comment { text: s, .. } => self.push(s),
+			Node::Prefix(p, x) => {
+				self.push(p);
+				self.node(x);
+			},
+			Node::List(open, nodes) => self.list(*open, nodes),
+			Node::Blank => {},
+		}
+	}
+
+	fn fits_after_space(&self, node: &Node) -> bool {
+		flat(node).is_some_and(|s| self.col + 1 + s.chars().count() <= self.width)
+	}
+
+	// a list that doesn't fit on one line
+	fn list(&mut self, open: char, nodes: &[Node]) {
+		let open_col = self.col;
+		// how many arguments stay on the first line, and where the lines after it start
+		// qexprs are laid out the same way, since they're often code waiting to be evaluated
+		// data fills each line as far as it can instead
+		let (hang, indent, fill) = match nodes.first() {
+			Some(Node::Atom(head)) if is_symbol(head) => {
+				match BODY_FORMS.iter().find(|(name, _)| name == head) {
+					Some((_, n)) => (*n, open_col + BODY_INDENT, false),
+					// a call - the rest line up under the first argument
+					None => (1, open_col + head.chars().count() + 2, false),
+				}
+			},
+			// data - everything lines up under the first element
+			_ => (0, open_col + 1, true),
+		};
+		self.push(&open.to_string());
+		// whether the next node has to start a new line, and whether to leave a blank one
+		let mut must_break = false;
+		let mut blank = false;
+		// nodes placed so far, not counting comments
+		let mut placed = 0;
+		for (i, node) in nodes.iter().enumerate() {
+			match node {
+				Node::Blank => {
+					blank = true;
+					continue;
+				},
+				Node::Comment { trailing: true, .. } => self.push(" "),
+				Node::Comment { .. } => self.newline(indent, blank),
+				_ if i == 0 => {},
+				_ if must_break || blank => self.newline(indent, blank),
+				_ if placed <= hang || fill && self.fits_after_space(node) => self.push(" "),
+				_ => self.newline(indent, blank),
+			}
+			self.node(node);
+			must_break = is_line_comment(node);
+			blank = false;
+			if !matches!(node, Node::Comment { .. }) {
+				placed += 1;
+			}
+		}
+		// a ; comment runs to the end of the line, so the bracket has to go on the next one
+		if must_break {
+			self.newline(indent, false);
+		}
+		self.push(if open == '(' { ")" } else { "}" });
+	}
+}
+
+// Lay out a whole file - top-level forms each start a line, and it ends with a newline
+pub fn format_nodes(nodes: &[Node], width: usize) -> String {
+	let mut printer = Printer {
+		out: String::new(),
+		col: 0,
+		width,
+	};
+	let mut blank = false;
+	for (i, node) in nodes.iter().enumerate() {
+		match node {
+			Node::Blank => {
+				blank = true;
+				continue;
+			},
+			Node::Comment { trailing: true, .. } if i > 0 => printer.push(" "),
+			_ if i > 0 => printer.newline(0, blank),
+			_ => {},
+		}
+		printer.node(node);
+		blank = false;
+	}
+	if !nodes.is_empty() {
+		printer.out.push('\n');
+	}
+	printer.out
+}
+
+// Reformat source code, keeping its comments
+// Every syntax error is reported, the same way the REPL does
+pub fn format_source(s: &str, width: usize) -> Result<String> {
+	let (_, diagnostics) = read_recovering(s.as_bytes())?;
+	if !diagnostics.is_empty() {
+		return Err(Error::Syntax(diagnostics));
+	}
+	Ok(format_nodes(&read_cst(s)?, width))
+}
+
+// A value laid out to fit in width, given that it starts in column col
+pub fn format_value(v: &Lval, col: usize, width: usize) -> String {
+	let mut printer = Printer {
+		out: String::new(),
+		col,
+		width,
+	};
+	printer.node(&lval_node(v));
+	printer.out
+}
@@ -0,0 +1,453 @@
+// A hand-written reader for the same syntax as blispr.pest
+// It pulls one top-level form at a time out of any BufRead, a line at a time, so a big file
+// can start evaluating before the rest of it has been read
+// In recovering mode it carries on past syntax errors, collecting a Diagnostic for each one
+use crate::{
+	error::{Error, Result},
+	lval::{add, at_line, character, keyword, named_char, num, qexpr, sexpr, sym, Lval},
+	parse::{int_value, Form},
+};
+use log::debug;
+use pest::unicode::{LETTER, MARK, NUMBER};
+use std::{fmt, io::BufRead};
+
+// symbol_char in the grammar
+pub fn is_symbol_char(c: char) -> bool {
+	LETTER(c) || MARK(c) || NUMBER(c) || "_+-*/%^\\=<>!&?.:$@".contains(c)
+}
+
+// Whether digits (no sign) is a whole num in the grammar - radix prefix, digits and _ separators
+fn is_int_literal(digits: &str) -> bool {
+	let (rest, is_digit): (&str, fn(char) -> bool) = if let Some(rest) = digits.strip_prefix("0x") {
+		(rest, |c| c.is_ascii_hexdigit())
+	} else if let Some(rest) = digits.strip_prefix("0o") {
+		(rest, |c| ('0'..='7').contains(&c))
+	} else if let Some(rest) = digits.strip_prefix("0b") {
+		(rest, |c| c == '0' || c == '1')
+	} else {
+		// plain decimals can't start with _
+		return digits.starts_with(|c: char| c.is_ascii_digit())
+			&& digits.chars().all(|c| c == '_' || c.is_ascii_digit());
+	};
+	rest.chars().any(is_digit) && rest.chars().all(|c| c == '_' || is_digit(c))
+}
+
+// A syntax error found by a recovering reader, from start up to (not including) end
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+	pub start: (usize, usize),
+	pub end: (usize, usize),
+	pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (line, col) = self.start;
+		write!(f, "line {line}, column {col}: {}", self.message)
+	}
+}
+
+fn closing(open: char) -> char {
+	if open == '(' {
+		')'
+	} else {
+		'}'
+	}
+}
+
+pub struct Reader<R> {
+	input: R,
+	// what's left of the current line
+	line_buf: Vec<char>,
+	next: usize,
+	// position of the next unread character, counting from 1
+	line: usize,
+	col: usize,
+	// text of the form being read, for Form::source - None between forms
+	source: Option<String>,
+	started: bool,
+	// set after an error - there's no telling where the next form starts
+	stopped: bool,
+	// report syntax errors as diagnostics and keep going, instead of stopping
+	recovering: bool,
+	diagnostics: Vec<Diagnostic>,
+	// the brackets enclosing the current position, and where they were opened
+	open: Vec<(char, (usize, usize))>,
+}
+
+impl<R: BufRead> Reader<R> {
+	pub fn new(input: R) -> Self {
+		Self {
+			input,
+			line_buf: Vec::new(),
+			next: 0,
+			line: 1,
+			col: 1,
+			source: None,
+			started: false,
+			stopped: false,
+			recovering: false,
+			diagnostics: Vec::new(),
+			open: Vec::new(),
+		}
+	}
+
+	// A reader that skips over syntax errors, leaving out whatever didn't read
+	// Only I/O errors stop it
+	pub fn recovering(input: R) -> Self {
+		Self {
+			recovering: true,
+			..Self::new(input)
+		}
+	}
+
+	// (line, column) of the first character not read yet
+	pub fn location(&self) -> (usize, usize) {
+		(self.line, self.col)
+	}
+
+	// The next top-level form, or None once the input runs out
+	// After an error every call returns None, and location() says where it went wrong
+	pub fn read_form(&mut self) -> Result<Option<Form>> {
+		if self.stopped {
+			return Ok(None);
+		}
+		let ret = self.read_top_level();
+		if ret.is_err() {
+			self.stopped = true;
+		}
+		ret
+	}
+
+	fn read_top_level(&mut self) -> Result<Option<Form>> {
+		if !self.started {
+			self.started = true;
+			if self.peek()? == Some('#') && self.peek_second() == Some('!') {
+				while self.peek()?.is_some_and(|c| c != '\n') {
+					self.bump();
+				}
+			}
+		}
+		loop {
+			self.skip_trivia()?;
+			match self.peek()? {
+				None => return Ok(None),
+				Some(c @ (')' | '}')) => {
+					self.report_unexpected(format!("unexpected `{c}`"))?;
+					continue;
+				},
+				Some(_) => {},
+			}
+			let (line, col) = self.location();
+			self.source = Some(String::new());
+			let value = self.read_datum();
+			let source = self.source.take().unwrap_or_default();
+			// a recovering reader gets None for a form it couldn't read, so it moves on to the next
+			if let Some(value) = value? {
+				debug!("Read: {:?}", value);
+				return Ok(Some(Form {
+					value,
+					source,
+					line,
+					col,
+				}));
+			}
+		}
+	}
+
+	fn peek(&mut self) -> Result<Option<char>> {
+		if self.next == self.line_buf.len() {
+			let mut line = String::new();
+			if self.input.read_line(&mut line)? == 0 {
+				return Ok(None);
+			}
+			self.line_buf = line.chars().collect();
+			self.next = 0;
+		}
+		Ok(Some(self.line_buf[self.next]))
+	}
+
+	// the character after peek() - only used for two-character tokens, which never span lines
+	fn peek_second(&self) -> Option<char> {
+		self.line_buf.get(self.next + 1).copied()
+	}
+
+	// only call this after peek() has returned Some
+	fn bump(&mut self) -> char {
+		let c = self.line_buf[self.next];
+		self.next += 1;
+		if c == '\n' {
+			self.line += 1;
+			self.col = 1;
+		} else {
+			self.col += 1;
+		}
+		if let Some(source) = &mut self.source {
+			source.push(c);
+		}
+		c
+	}
+
+	// A syntax error from start up to here - fatal unless we're recovering
+	fn report(&mut self, start: (usize, usize), message: String) -> Result<()> {
+		let diagnostic = Diagnostic {
+			start,
+			end: self.location(),
+			message,
+		};
+		if !self.recovering {
+			return Err(Error::Parse(diagnostic.to_string()));
+		}
+		self.diagnostics.push(diagnostic);
+		Ok(())
+	}
+
+	// The next character can't go here - recovering steps over it, otherwise we stop in front of it
+	fn report_unexpected(&mut self, message: String) -> Result<()> {
+		let start = self.location();
+		if self.recovering {
+			self.bump();
+		}
+		self.report(start, message)
+	}
+
+	// The input ran out partway through something that started at start
+	// Outside recovery this is Error::Incomplete, so the REPL knows to keep reading
+	fn report_end(&mut self, start: (usize, usize), message: String) -> Result<()> {
+		if !self.recovering {
+			return Err(Error::Incomplete(self.line, self.col));
+		}
+		self.report(start, message)
+	}
+
+	// whitespace and comments
+	fn skip_space(&mut self) -> Result<()> {
+		while let Some(c) = self.peek()? {
+			match (c, self.peek_second()) {
+				(' ' | '\n' | '\r', _) => {
+					self.bump();
+				},
+				(';', _) => {
+					while self.peek()?.is_some_and(|c| c != '\n' && c != '\r') {
+						self.bump();
+					}
+				},
+				('/', Some('*')) => self.skip_block_comment()?,
+				_ => break,
+			}
+		}
+		Ok(())
+	}
+
+	// block comments nest
+	fn skip_block_comment(&mut self) -> Result<()> {
+		let (line, col) = self.location();
+		let mut depth = 0;
+		loop {
+			match (self.peek()?, self.peek_second()) {
+				(None, _) => {
+					return self
+						.report_end((line, col), format!("unclosed `/*` opened at {line}:{col}"))
+				},
+				(Some('/'), Some('*')) => {
+					self.bump();
+					self.bump();
+					depth += 1;
+				},
+				(Some('*'), Some('/')) => {
+					self.bump();
+					self.bump();
+					depth -= 1;
+					if depth == 0 {
+						return Ok(());
+					}
+				},
+				_ => {
+					self.bump();
+				},
+			}
+		}
+	}
+
+	// True if a ' or #_ has run out of things to apply to, which has been reported
+	fn nothing_follows(&mut self, start: (usize, usize), prefix: &str) -> Result<bool> {
+		match self.peek()? {
+			None => {
+				self.report_end(start, format!("nothing after `{prefix}`"))?;
+				Ok(true)
+			},
+			Some(')' | '}') => {
+				self.report(start, format!("nothing after `{prefix}`"))?;
+				Ok(true)
+			},
+			Some(_) => Ok(false),
+		}
+	}
+
+	// whitespace, comments and #_ discarded expressions
+	fn skip_trivia(&mut self) -> Result<()> {
+		loop {
+			self.skip_space()?;
+			if self.peek()? == Some('#') && self.peek_second() == Some('_') {
+				let start = self.location();
+				self.bump();
+				self.bump();
+				self.skip_space()?;
+				if !self.nothing_follows(start, "#_")? {
+					self.read_datum()?;
+				}
+			} else {
+				return Ok(());
+			}
+		}
+	}
+
+	// One expression, starting at the next character
+	// None if it couldn't be read, which only happens when recovering
+	fn read_datum(&mut self) -> Result<Option<Box<Lval>>> {
+		let start = self.location();
+		let Some(c) = self.peek()? else {
+			self.report_end(start, "unexpected end of input".to_string())?;
+			return Ok(None);
+		};
+		match c {
+			'(' | '{' => self.read_list(c).map(Some),
+			'\'' => {
+				self.bump();
+				self.skip_space()?;
+				if self.nothing_follows(start, "'")? {
+					return Ok(None);
+				}
+				let Some(quoted) = self.read_datum()? else {
+					return Ok(None);
+				};
+				let mut ret = sexpr();
+				add(&mut ret, &sym("quote"))?;
+				add(&mut ret, &quoted)?;
+				Ok(Some(ret))
+			},
+			'#' if self.peek_second() == Some('\\') => self.read_char(),
+			c if is_symbol_char(c) => self.read_atom(),
+			c => {
+				self.report_unexpected(format!("unexpected {c:?}"))?;
+				Ok(None)
+			},
+		}
+	}
+
+	fn read_list(&mut self, open: char) -> Result<Box<Lval>> {
+		let (line, col) = self.location();
+		let close = closing(open);
+		let mut ret = at_line(if open == '(' { sexpr() } else { qexpr() }, line);
+		self.bump();
+		self.open.push((open, (line, col)));
+		loop {
+			self.skip_trivia()?;
+			match self.peek()? {
+				None => {
+					self.report_end(
+						(line, col),
+						format!("unclosed `{open}` opened at {line}:{col}"),
+					)?;
+					break;
+				},
+				Some(c) if c == close => {
+					self.bump();
+					break;
+				},
+				Some(c @ (')' | '}')) => {
+					// if it closes something further out, this list is the one that's missing its bracket
+					let closes_outer = self.open[..self.open.len() - 1]
+						.iter()
+						.any(|(o, _)| closing(*o) == c);
+					let start = self.location();
+					if closes_outer {
+						self.report(
+							start,
+							format!("expected `{close}` before `{c}` - unclosed `{open}` opened at {line}:{col}"),
+						)?;
+						break;
+					}
+					self.report_unexpected(format!("unexpected `{c}`"))?;
+				},
+				Some(_) => {
+					if let Some(v) = self.read_datum()? {
+						add(&mut ret, &v)?;
+					}
+				},
+			}
+		}
+		self.open.pop();
+		Ok(ret)
+	}
+
+	// a run of symbol characters
+	fn read_word(&mut self) -> Result<String> {
+		let mut ret = String::new();
+		while let Some(c) = self.peek()?.filter(|c| is_symbol_char(*c)) {
+			ret.push(c);
+			self.bump();
+		}
+		Ok(ret)
+	}
+
+	// #\a, or a named one like #\space
+	fn read_char(&mut self) -> Result<Option<Box<Lval>>> {
+		let start = self.location();
+		self.bump();
+		self.bump();
+		let Some(first) = self.peek()? else {
+			self.report_end(start, "nothing after `#\\`".to_string())?;
+			return Ok(None);
+		};
+		self.bump();
+		let rest = self.read_word()?;
+		if rest.is_empty() {
+			return Ok(Some(character(first)));
+		}
+		if let Some(c) = named_char(&format!("{first}{rest}")) {
+			return Ok(Some(character(c)));
+		}
+		self.report(start, format!("unknown character `#\\{first}{rest}`"))?;
+		Ok(None)
+	}
+
+	// a number, keyword or symbol
+	fn read_atom(&mut self) -> Result<Option<Box<Lval>>> {
+		let start = self.location();
+		let word = self.read_word()?;
+		let unsigned = word.strip_prefix(['+', '-']).unwrap_or(&word);
+		// anything starting like a number has to be one
+		if unsigned.starts_with(|c: char| c.is_ascii_digit()) {
+			if !is_int_literal(unsigned) {
+				self.report(start, format!("invalid number `{word}`"))?;
+				return Ok(None);
+			}
+			if let Some(n) = int_value(&word) {
+				return Ok(Some(num(n)));
+			}
+			self.report(start, "integer literal out of range".to_string())?;
+			return Ok(None);
+		}
+		match word.strip_prefix(':') {
+			Some(name) if !name.is_empty() => Ok(Some(keyword(name))),
+			_ => Ok(Some(sym(&word))),
+		}
+	}
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+	type Item = Result<Form>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.read_form().transpose()
+	}
+}
+
+// Read everything there is, carrying on past syntax errors
+// Gives back whatever did read, for tooling that wants to see every mistake at once
+pub fn read_recovering<R: BufRead>(input: R) -> Result<(Vec<Form>, Vec<Diagnostic>)> {
+	let mut reader = Reader::recovering(input);
+	let forms = reader.by_ref().collect::<Result<Vec<Form>>>()?;
+	Ok((forms, reader.diagnostics))
+}
@@ -1,44 +1,98 @@
 use crate::{
-	error::{BlisprResult, Result},
+	budget::Limits,
+	capability::Capabilities,
+	config::{Config, EditMode},
+	debugger::{Breakpoint, Debugger},
+	error::{Error, Result},
+	gc,
+	helper::{BlisprHelper, ContinueForm},
 	lenv::Lenv,
-	parse::eval_str,
-	Opt,
+	lint::{lint_source, Severity},
+	lsp,
+	lval::{add, num, qexpr, sexpr, sym, Lval},
+	parse::{eval_form, int_value},
+	pretty::format_source,
+	reader::Reader,
+	session::Session,
+	Backend, Command, FmtOpt, LintFormat, LintOpt, Opt,
+};
+use log::{debug, info, warn, LevelFilter};
+use rustyline::{
+	error::ReadlineError, history::DefaultHistory, Editor, EventHandler, KeyCode, KeyEvent,
+	Modifiers,
 };
-use log::{debug, info, warn};
-use rustyline::{error::ReadlineError, DefaultEditor};
 use std::{
-	env::set_var,
-	fs::File,
-	io::{BufReader, Read},
-	path::PathBuf,
+	env,
+	fs::{self, File},
+	io::{self, BufRead, BufReader, IsTerminal, Read},
+	path::{Path, PathBuf},
+	rc::Rc,
+	time::Duration,
 };
 
-fn print_eval_result(v: BlisprResult) {
-	match v {
-		Ok(res) => println!("{res}"),
-		Err(e) => eprintln!("Error: {e}"),
-	}
+// switch debug output on or off - the logger always lets it through, this is the only gate
+pub fn set_debug(on: bool) {
+	log::set_max_level(if on {
+		LevelFilter::Debug
+	} else {
+		LevelFilter::Error
+	});
 }
 
-fn repl(e: &mut Lenv) -> Result<()> {
-	println!("Blispr v{}", env!("CARGO_PKG_VERSION"));
-	println!("Use exit(), Ctrl-C, or Ctrl-D to exit prompt");
+// Returns the status code passed to (exit), or 0 when the prompt is closed
+fn repl(e: &mut Lenv, backend: Backend, config: &Config) -> Result<i32> {
+	if config.banner {
+		println!("Blispr v{}", env!("CARGO_PKG_VERSION"));
+		println!("Use exit(), Ctrl-C, or Ctrl-D to exit prompt");
+	}
 	debug!("Debug mode enabled");
 
-	let mut rl = DefaultEditor::new()?;
-	if rl.load_history("./.blispr-history.txt").is_err() {
+	let rl_config = rustyline::Config::builder()
+		.max_history_size(config.history_size)?
+		.edit_mode(match config.edit_mode {
+			EditMode::Emacs => rustyline::EditMode::Emacs,
+			EditMode::Vi => rustyline::EditMode::Vi,
+		})
+		.build();
+	let mut rl: Editor<BlisprHelper, DefaultHistory> = Editor::with_config(rl_config)?;
+	// only colour output meant for a person - see https://no-color.org
+	let color = env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal();
+	rl.set_helper(Some(BlisprHelper::new(e, color)));
+	rl.bind_sequence(
+		KeyEvent(KeyCode::Enter, Modifiers::NONE),
+		EventHandler::Conditional(Box::new(ContinueForm)),
+	);
+	if rl.load_history(&config.history).is_err() && config.banner {
 		println!("No history found.");
 	}
 
+	let mut session = Session::new(backend);
+	let mut status = 0;
 	loop {
-		let input = rl.readline("blispr> ");
+		let input = rl.readline(&config.prompt);
 
 		match input {
 			Ok(line) => {
-				rl.add_history_entry(&line)?; // .as_ref()
-							 // if eval_str is an error, we want to catch it here, inside the loop, but still show the next prompt
-							 // just using ? would bubble it up to main()
-				print_eval_result(eval_str(e, &line));
+				if line.trim().is_empty() {
+					continue;
+				}
+				rl.add_history_entry(line.trim_end())?; // .as_ref()
+				// if eval_str is an error, we want to catch it here, inside the loop, but still show the next prompt
+				// just using ? would bubble it up to main()
+				match session.input(e, &line) {
+					Err(Error::Exit(code)) => {
+						println!("Goodbye!");
+						status = code;
+						break;
+					},
+					Err(err) => eprintln!("Error: {err}"),
+					Ok(Some(res)) => println!("{res}"),
+					Ok(None) => {},
+				}
+				// completions should include anything that line defined
+				if let Some(helper) = rl.helper_mut() {
+					helper.refresh(e);
+				}
 			},
 			Err(ReadlineError::Interrupted) => {
 				info!("CTRL-C");
@@ -54,35 +108,260 @@ fn repl(e: &mut Lenv) -> Result<()> {
 			},
 		}
 	}
-	rl.save_history("./.blispr-history.txt")?;
-	Ok(())
+	if let Some(dir) = config.history.parent() {
+		fs::create_dir_all(dir)?;
+	}
+	rl.save_history(&config.history)?;
+	Ok(status)
 }
 
-fn file_contents(path: PathBuf) -> Result<String> {
-	let file = File::open(path)?;
-	let mut bfr = BufReader::new(file);
+// The path "-" reads from stdin instead
+pub fn file_contents(path: PathBuf) -> Result<String> {
 	let mut program = String::new();
-	bfr.read_to_string(&mut program)?;
+	if path.as_os_str() == "-" {
+		io::stdin().read_to_string(&mut program)?;
+	} else {
+		let file = File::open(path)?;
+		let mut bfr = BufReader::new(file);
+		bfr.read_to_string(&mut program)?;
+	}
 	Ok(program)
 }
 
-pub fn run(opt: Opt) -> Result<()> {
-	// enable debug output if needed
-	if opt.debug {
-		set_var("RUST_LOG", "blispr=debug");
+// Like file_contents, but read as it's needed
+fn open_input(path: &Path) -> Result<Box<dyn BufRead>> {
+	if path.as_os_str() == "-" {
+		Ok(Box::new(io::stdin().lock()))
+	} else {
+		Ok(Box::new(BufReader::new(File::open(path)?)))
+	}
+}
+
+// `blispr fmt` - rewrites each file that needs it, or with --check returns 1 if any do
+// A file with syntax errors is left alone, and the exit status is the parse error one
+fn fmt(opt: &FmtOpt) -> Result<i32> {
+	let stdin = [PathBuf::from("-")];
+	let files = if opt.files.is_empty() {
+		&stdin[..]
+	} else {
+		&opt.files
+	};
+	let mut status = 0;
+	for path in files {
+		let mut source = String::new();
+		open_input(path)?.read_to_string(&mut source)?;
+		// a file that doesn't parse is reported, and the rest are still formatted
+		let formatted = match format_source(&source, opt.width) {
+			Ok(formatted) => formatted,
+			Err(e) => {
+				eprintln!("{}: {e}", path.display());
+				status = status.max(e.exit_code());
+				continue;
+			},
+		};
+		if opt.check {
+			if formatted != source {
+				println!("{}", path.display());
+				status = status.max(1);
+			}
+		} else if path.as_os_str() == "-" {
+			print!("{formatted}");
+		} else if formatted != source {
+			fs::write(path, formatted)?;
+		}
+	}
+	Ok(status)
+}
+
+// `blispr lint` - returns 1 if it found any errors, warnings alone don't fail
+fn lint(opt: &LintOpt) -> Result<i32> {
+	let stdin = [PathBuf::from("-")];
+	let files = if opt.files.is_empty() {
+		&stdin[..]
+	} else {
+		&opt.files
+	};
+	let mut status = 0;
+	for path in files {
+		let mut source = String::new();
+		open_input(path)?.read_to_string(&mut source)?;
+		let file = path.display().to_string();
+		for found in lint_source(&source)? {
+			match opt.format {
+				LintFormat::Human => println!("{file}:{found}"),
+				LintFormat::Json => println!("{}", found.to_json(&file)),
+			}
+			if found.check.severity() == Severity::Error {
+				status = 1;
+			}
+		}
+	}
+	Ok(status)
+}
+
+// Script arguments as a qexpr - anything that looks like a number is one
+fn script_args(args: &[String]) -> Result<Box<Lval>> {
+	let mut ret = qexpr();
+	for arg in args {
+		match int_value(arg) {
+			Some(n) => add(&mut ret, &num(n))?,
+			None => add(&mut ret, &sym(arg))?,
+		}
+	}
+	Ok(ret)
+}
+
+// Evaluate a program one top-level form at a time, so errors can say which form failed
+// Only the last value is printed, unless --echo asks for all of them
+// Returns the status of the first error that --continue-on-error carried on past
+// Forms are read as they're needed, so a long program starts running straight away
+fn run_forms<R: BufRead>(e: &mut Lenv, forms: Reader<R>, opt: &Opt) -> Result<i32> {
+	e.budget().reset();
+	// line breakpoints count from the top of each program
+	if let Some(debugger) = e.debugger() {
+		debugger.enter_file();
+	}
+	let mut status = 0;
+	let mut fail = |err: Error| {
+		if !opt.continue_on_error {
+			return Err(err);
+		}
+		eprintln!("Error: {err}");
+		if status == 0 {
+			status = err.exit_code();
+		}
+		Ok(())
+	};
+	let mut last = sexpr();
+	for (i, form) in forms.enumerate() {
+		// the reader stops at a syntax error - there's no telling where the next form starts
+		let mut form = match form {
+			Ok(form) => form,
+			Err(err) => {
+				fail(err)?;
+				break;
+			},
+		};
+		if opt.echo {
+			println!("blispr> {}", form.source);
+		}
+		match eval_form(e, &mut form.value, opt.backend()) {
+			Ok(res) => last = res,
+			Err(Error::Exit(code)) => return Err(Error::Exit(code)),
+			Err(err) => {
+				fail(Error::InForm(i + 1, form.line, form.col, Box::new(err)))?;
+				continue;
+			},
+		}
+		if opt.echo {
+			println!("{last}");
+		}
+	}
+	if !opt.echo && !opt.quiet {
+		println!("{last}");
+	}
+	Ok(status)
+}
+
+// Returns the status code the process should exit with
+pub fn run(opt: &Opt) -> Result<i32> {
+	// RUST_LOG is respected unless --debug is passed
+	// otherwise our debug output is let through, and set_debug decides if it's shown
+	let filters = env::var("RUST_LOG").ok().filter(|_| !opt.debug);
+	pretty_env_logger::formatted_builder()
+		.parse_filters(filters.as_deref().unwrap_or("blispr=debug"))
+		.init();
+	if filters.is_none() {
+		set_debug(opt.debug);
+	}
+
+	match &opt.command {
+		Some(Command::Fmt(fmt_opt)) => return fmt(fmt_opt),
+		Some(Command::Lint(lint_opt)) => return lint(lint_opt),
+		Some(Command::Lsp) => return lsp::serve(io::stdin().lock(), io::stdout().lock()),
+		None => {},
 	}
-	pretty_env_logger::init();
 
 	// Initialize global environment
 	// First arg is optional lookup map, second is optional parent env
 	// The root env starts empty (except for builtins) and has no parent
 	let global_env = &mut Lenv::new(None, None);
+	global_env.set_limits(Limits {
+		max_steps: opt.max_steps,
+		max_depth: opt.max_depth,
+		max_cells: opt.max_cells,
+		timeout: opt.timeout.map(Duration::from_millis),
+	});
+	global_env.set_capabilities(
+		opt.deny
+			.iter()
+			.fold(Capabilities::all(), |caps, c| caps.without(*c)),
+	);
+	if let Some(threshold) = opt.gc_threshold {
+		gc::configure(gc::Settings {
+			threshold: (threshold > 0).then_some(threshold),
+		});
+	}
 
-	if let Some(f) = opt.input {
-		// if input file passed, eval its contents
-		print_eval_result(eval_str(global_env, &file_contents(f)?));
-	} else {
-		repl(global_env)?;
+	// Without --input or --eval the first argument is the script, so #!/usr/bin/env blispr works
+	// Piping a program in counts as a script too
+	let mut args = opt.args.as_slice();
+	let input = match (&opt.input, args.split_first()) {
+		(Some(f), _) => Some(f.clone()),
+		(None, Some((script, rest))) if opt.eval.is_empty() => {
+			args = rest;
+			Some(PathBuf::from(script))
+		},
+		(None, _) if opt.eval.is_empty() && !io::stdin().is_terminal() => Some(PathBuf::from("-")),
+		(None, _) => None,
+	};
+	global_env.put("*args*".to_string(), script_args(args)?);
+
+	// the REPL always has one, so :break and (break ()) work there
+	let interactive = input.is_none() && opt.eval.is_empty();
+	if opt.debugging() || interactive {
+		if opt.debugging() && input.as_deref() == Some(Path::new("-")) {
+			return Err(Error::Command(
+				"the debugger reads its commands from stdin, so the program can't come from there too"
+					.to_string(),
+			));
+		}
+		let debugger = Debugger::stdio();
+		for breakpoint in &opt.breakpoints {
+			debugger.add_breakpoint(Breakpoint::parse(breakpoint));
+		}
+		// with nowhere else to stop, stop at the start
+		if opt.debugger && opt.breakpoints.is_empty() {
+			debugger.step();
+		}
+		global_env.set_debugger(Some(Rc::new(debugger)));
+	}
+
+	if interactive {
+		let config = Config::load(opt)?;
+		return repl(global_env, opt.backend(), &config);
+	}
+
+	// the script runs first, then each --eval in order
+	let mut programs = Vec::new();
+	if let Some(f) = input {
+		programs.push(open_input(&f)?);
+	}
+	programs.extend(
+		opt.eval
+			.iter()
+			.map(|expr| Box::new(expr.as_bytes()) as Box<dyn BufRead>),
+	);
+	// without --continue-on-error the first error stops everything, and main turns it into the exit status
+	let mut status = 0;
+	for program in programs {
+		let program_status = match run_forms(global_env, Reader::new(program), opt) {
+			Err(Error::Exit(code)) => return Ok(code),
+			res => res?,
+		};
+		if status == 0 {
+			status = program_status;
+		}
 	}
-	Ok(())
+	Ok(status)
 }
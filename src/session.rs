@@ -0,0 +1,215 @@
+// State for one REPL session, and the :commands that inspect and control it
+use crate::{
+	debugger::{Breakpoint, Debugger},
+	doc::{describe, signature},
+	error::{BlisprResult, Error, Result},
+	lenv::Lenv,
+	lval::{sexpr, Func, Lval},
+	parse::{eval_form, eval_str},
+	pretty::{format_value, WIDTH},
+	reader::read_recovering,
+	run::{file_contents, set_debug},
+	Backend,
+};
+use std::{fs, path::PathBuf, rc::Rc, time::Instant};
+
+const HELP: &str = "\
+:help               show this message
+:env [filter]       list bindings, optionally only names containing filter
+:doc name           describe a builtin or show a definition
+:type expr          evaluate expr and show the type of the result
+:time expr          evaluate expr and show how long it took
+:load file          evaluate a file in the current environment
+:reload             load the last file again
+:reset              start over with a fresh environment
+:debug on|off       turn debug logging on or off
+:break [name|line]  stop before calls to name, or calls on line of a :loaded file - with no argument list them
+:clear [name|line]  remove a breakpoint, or all of them
+:step expr          evaluate expr, stopping before its first call
+:save file          write this session's definitions to file";
+
+const COMMANDS: [&str; 13] = [
+	"help", "env", "doc", "type", "time", "load", "reload", "reset", "debug", "break", "clear",
+	"step", "save",
+];
+
+// The text after the colon if line is a :command
+// Anything else starting with a colon is a form, like the keyword :foo
+pub fn command_text(line: &str) -> Option<&str> {
+	let rest = line.trim().strip_prefix(':')?;
+	let name = rest.split(char::is_whitespace).next().unwrap_or_default();
+	COMMANDS.contains(&name).then_some(rest)
+}
+
+// (def ...) at the top level
+fn is_def(form: &Lval) -> bool {
+	match form {
+		Lval::Sexpr(cells) => cells
+			.first()
+			.is_some_and(|head| head.as_string().is_ok_and(|s| s == "def")),
+		_ => false,
+	}
+}
+
+pub struct Session {
+	backend: Backend,
+	// the file :reload will load
+	last_loaded: Option<PathBuf>,
+	// source of every top-level def evaluated so far, comments and all, for :save
+	definitions: Vec<String>,
+}
+
+impl Session {
+	pub fn new(backend: Backend) -> Self {
+		Self {
+			backend,
+			last_loaded: None,
+			definitions: Vec::new(),
+		}
+	}
+
+	// Evaluate a line of input, remembering any definitions it made
+	// Nothing runs if there are syntax errors - they're all reported together
+	pub fn eval(&mut self, e: &mut Lenv, s: &str) -> BlisprResult {
+		self.eval_source(e, s, false)
+	}
+
+	// Run a line typed at the REPL - the value of a form, or None for a :command, which prints its own output
+	pub fn input(&mut self, e: &mut Lenv, line: &str) -> Result<Option<Box<Lval>>> {
+		match command_text(line) {
+			Some(command) => self.command(e, command).map(|()| None),
+			None => self.eval(e, line).map(Some),
+		}
+	}
+
+	// from_file says whether line breakpoints apply
+	fn eval_source(&mut self, e: &mut Lenv, s: &str, from_file: bool) -> BlisprResult {
+		let (forms, diagnostics) = read_recovering(s.as_bytes())?;
+		if !diagnostics.is_empty() {
+			return Err(Error::Syntax(diagnostics));
+		}
+		e.budget().reset();
+		let debugger = e.debugger();
+		if let Some(debugger) = debugger.as_ref().filter(|_| from_file) {
+			debugger.enter_file();
+		}
+		let ret = forms.into_iter().try_fold(sexpr(), |_, mut form| {
+			let def = is_def(&form.value);
+			let ret = eval_form(e, &mut form.value, self.backend)?;
+			if def {
+				self.definitions.push(form.source);
+			}
+			Ok(ret)
+		});
+		// stepping stops with the input it started in
+		if let Some(debugger) = debugger {
+			debugger.finish();
+		}
+		ret
+	}
+
+	fn load(&mut self, e: &mut Lenv, path: PathBuf) -> Result<()> {
+		let program = file_contents(path.clone())?;
+		self.last_loaded = Some(path);
+		println!("{}", self.eval_source(e, &program, true)?);
+		Ok(())
+	}
+
+	// The debugger only sees calls the tree-walker makes
+	fn debugger(&self, e: &Lenv) -> Result<Rc<Debugger>> {
+		if self.backend == Backend::Vm {
+			return Err(Error::Command(
+				"the debugger needs --backend tree".to_string(),
+			));
+		}
+		e.debugger()
+			.ok_or_else(|| Error::Command("no debugger attached".to_string()))
+	}
+
+	// Run a :command - line is everything after the colon
+	pub fn command(&mut self, e: &mut Lenv, line: &str) -> Result<()> {
+		let (cmd, arg) = match line.trim().split_once(char::is_whitespace) {
+			Some((cmd, arg)) => (cmd, arg.trim()),
+			None => (line.trim(), ""),
+		};
+		match (cmd, arg) {
+			("help", _) => println!("{HELP}"),
+			("env", filter) => {
+				let mut entries: Vec<(&str, &Lval)> =
+					e.entries().filter(|(k, _)| k.contains(filter)).collect();
+				entries.sort_by_key(|(k, _)| *k);
+				for (k, v) in entries {
+					println!("{k}: {v}");
+				}
+			},
+			("doc", name) if !name.is_empty() => match *e.get(name)? {
+				Lval::Fun(Func::Builtin(ref builtin, _)) => println!(
+					"({builtin} {})\n  {}",
+					signature(builtin).unwrap_or_default(),
+					describe(builtin).unwrap_or_default()
+				),
+				// long definitions are laid out over several lines
+				ref v => println!("{name}: {}", format_value(v, name.len() + 2, WIDTH)),
+			},
+			("type", expr) if !expr.is_empty() => {
+				println!("{}", eval_str(e, expr, self.backend)?.type_name());
+			},
+			("time", expr) if !expr.is_empty() => {
+				let start = Instant::now();
+				let ret = eval_str(e, expr, self.backend)?;
+				println!("{ret}\ntook {:?}", start.elapsed());
+			},
+			("load", path) if !path.is_empty() => self.load(e, PathBuf::from(path))?,
+			("reload", _) => match self.last_loaded.clone() {
+				Some(path) => self.load(e, path)?,
+				None => return Err(Error::Command("nothing loaded yet".to_string())),
+			},
+			("reset", _) => {
+				let mut fresh = Lenv::new(None, None);
+				fresh.set_limits(e.budget().limits());
+				fresh.set_capabilities(e.capabilities());
+				fresh.set_debugger(e.debugger());
+				*e = fresh;
+				self.definitions.clear();
+			},
+			("debug", "on") => set_debug(true),
+			("debug", "off") => set_debug(false),
+			("break", "") => {
+				let breakpoints = self.debugger(e)?.breakpoints();
+				if breakpoints.is_empty() {
+					println!("no breakpoints");
+				}
+				for breakpoint in breakpoints {
+					println!("{breakpoint}");
+				}
+			},
+			("break", target) => self.debugger(e)?.add_breakpoint(Breakpoint::parse(target)),
+			("clear", "") => self.debugger(e)?.clear_breakpoints(),
+			("clear", target) => {
+				if !self
+					.debugger(e)?
+					.remove_breakpoint(&Breakpoint::parse(target))
+				{
+					return Err(Error::Command(format!("no breakpoint at {target}")));
+				}
+			},
+			("step", expr) if !expr.is_empty() => {
+				self.debugger(e)?.step();
+				println!("{}", self.eval(e, expr)?);
+			},
+			("save", path) if !path.is_empty() => {
+				let mut source = self.definitions.join("\n");
+				source.push('\n');
+				fs::write(path, source)?;
+				println!("saved {} definitions to {path}", self.definitions.len());
+			},
+			_ => {
+				return Err(Error::Command(format!(
+					"unknown command :{} - try :help",
+					line.trim()
+				)))
+			},
+		}
+		Ok(())
+	}
+}
@@ -1,5 +1,34 @@
 // These are integration tests - I'm not clear on how best to unit test this yet
-use crate::{lenv::Lenv, lval::Lval, parse::eval_str};
+// Every program is run against both the tree-walker and the VM
+use crate::{
+	budget::Limits,
+	capability::{Capabilities, Capability},
+	compile::{compile, Chunk, CompiledBody, Op},
+	config::{Config, EditMode},
+	debugger::{Breakpoint, Debugger},
+	doc::arity,
+	error::{Error, EXIT_IO, EXIT_PARSE, EXIT_RUNTIME},
+	gc::{self, Gc},
+	helper::BlisprHelper,
+	lenv::Lenv,
+	lint::{lint_source, Check},
+	lsp::{read_message, serve, write_message},
+	lval::{add, keyword, qexpr, sym, Func, Lval},
+	parse::{eval_str, is_incomplete, read_forms, Form},
+	pretty::{format_source, format_value},
+	reader::{read_recovering, Reader},
+	run::file_contents,
+	session::Session,
+	Backend, Opt,
+};
+use clap::Parser;
+use rustyline::{
+	completion::Completer, highlight::Highlighter, hint::Hinter, history::DefaultHistory, Context,
+};
+use serde_json::json;
+use std::{
+	cell::RefCell, collections::HashMap, env, fs, io, path::PathBuf, rc::Rc, time::Duration,
+};
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
@@ -7,11 +36,24 @@ use pretty_assertions::assert_eq;
 #[cfg(test)]
 fn test_blispr(test_str: &str, expected: &Lval) {
 	assert_eq!(
-		&*eval_str(&mut Lenv::new(None, None), test_str).unwrap(),
+		&*eval_str(&mut Lenv::new(None, None), test_str, Backend::Tree).unwrap(),
+		expected
+	);
+	assert_eq!(
+		&*eval_str(&mut Lenv::new(None, None), test_str, Backend::Vm).unwrap(),
 		expected
 	);
 }
 
+#[cfg(test)]
+fn test_blispr_limited(test_str: &str, limits: Limits) -> [Error; 2] {
+	[Backend::Tree, Backend::Vm].map(|backend| {
+		let mut env = Lenv::new(None, None);
+		env.set_limits(limits);
+		eval_str(&mut env, test_str, backend).unwrap_err()
+	})
+}
+
 #[test]
 fn test_add_two_numbers() {
 	test_blispr("(+ 1 2)", &Lval::Num(3));
@@ -101,7 +143,7 @@ fn test_head() {
 fn test_tail() {
 	test_blispr(
 		"(tail {1 2 3})",
-		&Lval::Qexpr(vec![Box::new(Lval::Num(2)), Box::new(Lval::Num(3))]),
+		&Lval::Qexpr(vec![Box::new(Lval::Num(2)), Box::new(Lval::Num(3))].into()),
 	);
 }
 
@@ -109,11 +151,14 @@ fn test_tail() {
 fn test_cons() {
 	test_blispr(
 		"(cons 3 {4 5})",
-		&Lval::Qexpr(vec![
-			Box::new(Lval::Num(3)),
-			Box::new(Lval::Num(4)),
-			Box::new(Lval::Num(5)),
-		]),
+		&Lval::Qexpr(
+			vec![
+				Box::new(Lval::Num(3)),
+				Box::new(Lval::Num(4)),
+				Box::new(Lval::Num(5)),
+			]
+			.into(),
+		),
 	);
 }
 
@@ -126,11 +171,14 @@ fn test_len() {
 fn test_list() {
 	test_blispr(
 		"(list 1 2 3)",
-		&Lval::Qexpr(vec![
-			Box::new(Lval::Num(1)),
-			Box::new(Lval::Num(2)),
-			Box::new(Lval::Num(3)),
-		]),
+		&Lval::Qexpr(
+			vec![
+				Box::new(Lval::Num(1)),
+				Box::new(Lval::Num(2)),
+				Box::new(Lval::Num(3)),
+			]
+			.into(),
+		),
 	);
 }
 
@@ -138,12 +186,15 @@ fn test_list() {
 fn test_join() {
 	test_blispr(
 		"(join {1 2} {2 3})",
-		&Lval::Qexpr(vec![
-			Box::new(Lval::Num(1)),
-			Box::new(Lval::Num(2)),
-			Box::new(Lval::Num(2)),
-			Box::new(Lval::Num(3)),
-		]),
+		&Lval::Qexpr(
+			vec![
+				Box::new(Lval::Num(1)),
+				Box::new(Lval::Num(2)),
+				Box::new(Lval::Num(2)),
+				Box::new(Lval::Num(3)),
+			]
+			.into(),
+		),
 	);
 }
 
@@ -151,7 +202,7 @@ fn test_join() {
 fn test_init() {
 	test_blispr(
 		"(init {1 2 3})",
-		&Lval::Qexpr(vec![Box::new(Lval::Num(1)), Box::new(Lval::Num(2))]),
+		&Lval::Qexpr(vec![Box::new(Lval::Num(1)), Box::new(Lval::Num(2))].into()),
 	);
 }
 
@@ -203,3 +254,934 @@ fn test_input_file() {
 	let file_str = include_str!("../test.blispr");
 	test_blispr(file_str, &Lval::Num(311));
 }
+
+#[test]
+fn test_eval_non_qexpr() {
+	test_blispr("(eval 5)", &Lval::Num(5));
+}
+
+#[test]
+fn test_varargs_lambda() {
+	test_blispr(
+		"((\\ {x & xs} {cons x xs}) 1 2 3)",
+		&Lval::Qexpr(
+			vec![
+				Box::new(Lval::Num(1)),
+				Box::new(Lval::Num(2)),
+				Box::new(Lval::Num(3)),
+			]
+			.into(),
+		),
+	);
+}
+
+#[test]
+fn test_def_in_lambda_shadows_formal() {
+	test_blispr(
+		"((\\ {x} {tail (list (def {x} 2) x)}) 1)",
+		&Lval::Qexpr(vec![Box::new(Lval::Num(2))].into()),
+	);
+	test_blispr(
+		"(def {g} (\\ {x} {tail (list (eval {def {x} 5}) x)})) (g 1)",
+		&Lval::Qexpr(vec![Box::new(Lval::Num(5))].into()),
+	);
+}
+
+#[test]
+fn test_compile_resolves_locals() {
+	let mut body = Lval::Sexpr(Vec::new().into());
+	for s in ["+", "x", "y"] {
+		add(&mut body, &sym(s)).unwrap();
+	}
+	let chunk = compile(&body, &["x".to_string()]);
+	assert_eq!(
+		chunk.code,
+		vec![Op::Load(0), Op::Local(0), Op::Load(1), Op::Call(2)]
+	);
+	assert_eq!(chunk.names, vec!["+".to_string(), "y".to_string()]);
+}
+
+#[test]
+fn test_lambda_compiled_once() {
+	let mut env = Lenv::new(None, None);
+	eval_str(&mut env, "(def {sq} (\\ {x} {* x x}))", Backend::Vm).unwrap();
+	// every lookup is a copy, and they all share the body's chunk
+	let locals = ["x".to_string()];
+	let chunks: Vec<Rc<Chunk>> = (0..2)
+		.map(|_| match *env.get("sq").unwrap() {
+			Lval::Fun(Func::Lambda(_, _, body, compiled)) => compiled.chunk(&body, &locals),
+			_ => panic!("sq isn't a lambda"),
+		})
+		.collect();
+	assert!(Rc::ptr_eq(&chunks[0], &chunks[1]));
+	assert_eq!(
+		*eval_str(&mut env, "(sq 7)", Backend::Vm).unwrap(),
+		Lval::Num(49)
+	);
+}
+
+#[test]
+fn test_step_limit() {
+	let limits = Limits {
+		max_steps: Some(200),
+		..Limits::default()
+	};
+	for err in test_blispr_limited("((\\ {x} {x x}) (\\ {x} {x x}))", limits) {
+		assert!(matches!(err, Error::StepLimit(200)), "{err:?}");
+	}
+}
+
+#[test]
+fn test_depth_limit() {
+	let limits = Limits {
+		max_depth: Some(50),
+		..Limits::default()
+	};
+	for err in test_blispr_limited("((\\ {x} {x x}) (\\ {x} {x x}))", limits) {
+		assert!(matches!(err, Error::DepthLimit(50)), "{err:?}");
+	}
+}
+
+#[test]
+fn test_cell_limit() {
+	let limits = Limits {
+		max_cells: Some(1000),
+		..Limits::default()
+	};
+	let program = "(def {f} (\\ {x} {f (join x x)}))(f {1})";
+	for err in test_blispr_limited(program, limits) {
+		assert!(matches!(err, Error::CellLimit(1000)), "{err:?}");
+	}
+}
+
+#[test]
+fn test_timeout() {
+	let limits = Limits {
+		timeout: Some(Duration::ZERO),
+		..Limits::default()
+	};
+	for err in test_blispr_limited("(+ 1 2)", limits) {
+		assert!(matches!(err, Error::Timeout(_)), "{err:?}");
+	}
+}
+
+#[test]
+fn test_denied_capability() {
+	for backend in [Backend::Tree, Backend::Vm] {
+		let mut env = Lenv::new(None, None);
+		env.set_capabilities(Capabilities::all().without(Capability::Process));
+		let err = eval_str(&mut env, "(+ 1 ((\\ {x} {exit x}) 0))", backend).unwrap_err();
+		assert!(
+			matches!(err, Error::PermissionDenied(ref name, Capability::Process) if name == "exit"),
+			"{err:?}"
+		);
+		assert_eq!(
+			*eval_str(&mut env, "(+ 1 2)", backend).unwrap(),
+			Lval::Num(3)
+		);
+	}
+}
+
+#[test]
+fn test_gc() {
+	// a collection in the middle of a call keeps everything still in use, on the Lenv or mid-evaluation
+	test_blispr(
+		"(def {add} (\\ {a b} {+ a b})) (def {inc} (add 1)) (+ (inc 1) ((add 2) (len (gc ()))))",
+		&Lval::Num(12),
+	);
+
+	// an env holding a lambda that holds the same env - counting references alone never frees it
+	let before = gc::stats();
+	{
+		let env = Gc::new(HashMap::new());
+		let f = Lval::Fun(Func::Lambda(
+			env.clone(),
+			qexpr(),
+			qexpr(),
+			CompiledBody::default(),
+		));
+		env.bindings()
+			.borrow_mut()
+			.insert("f".to_string(), Box::new(f));
+	}
+	assert_eq!(gc::stats().live, before.live + 1);
+	assert_eq!(gc::collect(), 1);
+	let after = gc::stats();
+	assert_eq!(after.live, before.live);
+	assert_eq!(after.collected, before.collected + 1);
+
+	// a cycle something outside still holds onto is kept
+	let env = Gc::new(HashMap::new());
+	let f = Lval::Fun(Func::Lambda(
+		env.clone(),
+		qexpr(),
+		qexpr(),
+		CompiledBody::default(),
+	));
+	env.bindings()
+		.borrow_mut()
+		.insert("f".to_string(), Box::new(f));
+	assert_eq!(gc::collect(), 0);
+	assert!(env.bindings().borrow().contains_key("f"));
+
+	// with no threshold, only asking collects
+	gc::configure(gc::Settings { threshold: None });
+	let collections = gc::stats().collections;
+	for _ in 0..=gc::DEFAULT_THRESHOLD {
+		Gc::new(HashMap::new());
+	}
+	assert_eq!(gc::stats().collections, collections);
+	gc::configure(gc::Settings::default());
+}
+
+#[test]
+fn test_exit_unwinds() {
+	for backend in [Backend::Tree, Backend::Vm] {
+		let mut env = Lenv::new(None, None);
+		let err = eval_str(&mut env, "(+ 1 ((\\ {x} {exit x}) 3))", backend).unwrap_err();
+		assert!(matches!(err, Error::Exit(3)), "{err:?}");
+		let err = eval_str(&mut env, "(exit ())", backend).unwrap_err();
+		assert!(matches!(err, Error::Exit(0)), "{err:?}");
+		for status in ["256", "-1"] {
+			let err = eval_str(&mut env, &format!("(exit {status})"), backend).unwrap_err();
+			assert!(matches!(err, Error::WrongType(..)), "{err:?}");
+		}
+	}
+}
+
+#[test]
+fn test_empty_program() {
+	test_blispr("", &Lval::Sexpr(Vec::new().into()));
+}
+
+#[test]
+fn test_incomplete_input() {
+	assert!(is_incomplete("(def {f} (\\ {x}"));
+	assert!(is_incomplete("(+ 1 /* unterminated ) comment"));
+	assert!(!is_incomplete("(def {f} (\\ {x} {x}))"));
+	assert!(!is_incomplete("(+ 1 /* ( */ 2)"));
+	assert!(!is_incomplete("(+ 1 2))"));
+	assert!(is_incomplete("(+ 1 /* /* nested */ still open"));
+	assert!(!is_incomplete("(+ 1 ; (\n 2)"));
+}
+
+#[test]
+fn test_numeric_literals() {
+	test_blispr("(list 0xff 0o17 0b1010 1_000_000 -0x_10)", &{
+		let mut ret = Lval::Qexpr(Vec::new().into());
+		for n in [255, 15, 10, 1_000_000, -16] {
+			add(&mut ret, &Lval::Num(n)).unwrap();
+		}
+		ret
+	});
+	test_blispr("-9223372036854775808", &Lval::Num(i64::MIN));
+	match eval_str(
+		&mut Lenv::new(None, None),
+		"(+ 1\n 9223372036854775808)",
+		Backend::Tree,
+	) {
+		Err(Error::Parse(msg)) => {
+			assert!(msg.contains("integer literal out of range"), "{msg}");
+			assert!(msg.contains("2:2"), "{msg}");
+		},
+		res => panic!("expected a parse error, got {res:?}"),
+	}
+}
+
+#[test]
+fn test_symbols() {
+	test_blispr(
+		"(def {list? str->num set! *global* a.b λ -x} 1 2 3 4 5 6 7) (+ list? str->num set! *global* a.b λ -x)",
+		&Lval::Num(28),
+	);
+	test_blispr("(- -5)", &Lval::Num(5));
+	test_blispr("(def {-x} 7) (- 5 -x)", &Lval::Num(-2));
+	assert!(matches!(
+		eval_str(&mut Lenv::new(None, None), "(+ 1.5)", Backend::Tree),
+		Err(Error::Parse(_))
+	));
+	assert!(matches!(
+		eval_str(&mut Lenv::new(None, None), "5x", Backend::Tree),
+		Err(Error::Parse(_))
+	));
+}
+
+#[test]
+fn test_quote() {
+	test_blispr("'x", &Lval::Sym("x".to_string()));
+	test_blispr("(quote x)", &Lval::Sym("x".to_string()));
+	test_blispr("(eval (list '+ 1 2))", &Lval::Num(3));
+	test_blispr("(head {'x})", &read_forms("'x").unwrap()[0].value);
+	// the printer writes (quote x) back out the way it was read
+	for source in ["'x", "'(+ 1 'y)", "{a 'b}"] {
+		assert_eq!(read_forms(source).unwrap()[0].value.to_string(), source);
+	}
+	assert_eq!(read_forms("(quote x)").unwrap()[0].value.to_string(), "'x");
+}
+
+#[test]
+fn test_keywords() {
+	test_blispr(":tag", &Lval::Keyword("tag".into()));
+	test_blispr("(head {:tag})", &Lval::Keyword("tag".into()));
+	test_blispr("(== :a :a)", &Lval::Num(1));
+	test_blispr("(== :a :b)", &Lval::Num(0));
+	test_blispr("(== {1 :a} {1 :a})", &Lval::Num(1));
+	test_blispr("(keyword? :a)", &Lval::Num(1));
+	test_blispr("(keyword? 'a)", &Lval::Num(0));
+	test_blispr("(keyword->str :a-b)", &Lval::Sym("a-b".to_string()));
+	test_blispr("(def {a:b} 3) a:b", &Lval::Num(3));
+	// every :tag is the same allocation
+	match (*keyword("tag"), *keyword("tag")) {
+		(Lval::Keyword(x), Lval::Keyword(y)) => assert!(Rc::ptr_eq(&x, &y)),
+		_ => unreachable!(),
+	}
+}
+
+#[test]
+fn test_comments() {
+	test_blispr("(+ 1 ; the rest of the line (\n 2)", &Lval::Num(3));
+	test_blispr("(+ 1 /* outer /* inner */ still outer */ 2)", &Lval::Num(3));
+	test_blispr("(+ 1 #_ (* 100 100) 2)", &Lval::Num(3));
+	test_blispr("(len {1 #_ {2 3} 4})", &Lval::Num(2));
+	test_blispr("(+ 1 2) #_ (+ 3 4)", &Lval::Num(3));
+}
+
+#[test]
+fn test_repl_completion() {
+	let mut env = Lenv::new(None, None);
+	let mut helper = BlisprHelper::new(&env, false);
+	eval_str(
+		&mut env,
+		"(def {helper-fn} (\\ {x y} {+ x y}))",
+		Backend::Tree,
+	)
+	.unwrap();
+	helper.refresh(&env);
+
+	let history = DefaultHistory::new();
+	let ctx = Context::new(&history);
+	let (start, candidates) = helper.complete("(+ (hel", 7, &ctx).unwrap();
+	assert_eq!(start, 4);
+	let names: Vec<&str> = candidates.iter().map(|c| c.replacement.as_str()).collect();
+	assert_eq!(names, vec!["helper-fn"]);
+	// a multibyte character before the word isn't split
+	let line = "(+ “hel";
+	let (start, candidates) = helper.complete(line, line.len(), &ctx).unwrap();
+	assert_eq!(&line[start..], "hel");
+	assert_eq!(candidates.len(), 1);
+
+	assert_eq!(helper.hint("(cons ", 6, &ctx), Some("x {list}".to_string()));
+	assert_eq!(
+		helper.hint("(helper-fn ", 11, &ctx),
+		Some("{x y}".to_string())
+	);
+	assert_eq!(helper.hint("(helper-fn 1", 12, &ctx), None);
+}
+
+#[test]
+fn test_repl_highlighting() {
+	let helper = BlisprHelper::new(&Lenv::new(None, None), true);
+	let line = "(head {a}) /* hi */ nope";
+	let highlighted = helper.highlight(line, 0);
+	// the paren under the cursor and its partner
+	assert!(highlighted.starts_with("\x1b[1;7m(\x1b[0m\x1b[36mhead\x1b[0m"));
+	assert!(highlighted.contains("\x1b[1;7m)\x1b[0m"));
+	// quoted symbols aren't flagged, but free ones are
+	assert!(highlighted.contains("\x1b[35m{\x1b[0ma\x1b[35m}\x1b[0m"));
+	assert!(highlighted.contains("\x1b[90m/* hi */\x1b[0m"));
+	assert!(highlighted.ends_with("\x1b[4;31mnope\x1b[0m"));
+
+	let plain = BlisprHelper::new(&Lenv::new(None, None), false);
+	assert_eq!(plain.highlight(line, 0), line);
+
+	// #\( is a character rather than a bracket, and multibyte names stay whole
+	let highlighted = helper.highlight("(list #\\( λ)", 0);
+	assert!(highlighted.contains("\x1b[33m#\\(\x1b[0m"));
+	assert!(highlighted.contains("\x1b[1;7m)\x1b[0m"));
+	assert!(highlighted.contains("\x1b[4;31mλ\x1b[0m"));
+
+	// the indent Enter leaves on each line of an unfinished form is drawn as the continuation prompt
+	assert_eq!(plain.highlight("(+ 1\n     2)", 0), "(+ 1\n...> 2)");
+	assert!(helper
+		.highlight("(+ 1 ; one\n       2)", 0)
+		.contains("\n...>   \x1b[33m2\x1b[0m"));
+}
+
+#[test]
+fn test_characters() {
+	test_blispr("#\\a", &Lval::Char('a'));
+	test_blispr("(head {#\\space})", &Lval::Char(' '));
+	test_blispr("(char->int #\\newline)", &Lval::Num(10));
+	test_blispr("(int->char 955)", &Lval::Char('λ'));
+	test_blispr("(char-alphabetic? #\\λ)", &Lval::Num(1));
+	test_blispr("(char-numeric? #\\a)", &Lval::Num(0));
+	test_blispr(
+		"(chars->str (str->chars 'hello))",
+		&Lval::Sym("hello".to_string()),
+	);
+	test_blispr("(len (str->chars 'hello))", &Lval::Num(5));
+	let mut env = Lenv::new(None, None);
+	assert!(matches!(
+		eval_str(&mut env, "(chars->str {#\\a} {#\\b})", Backend::Tree),
+		Err(Error::NumArguments(1, 2))
+	));
+	assert!(matches!(
+		eval_str(&mut env, "(chars->str {})", Backend::Tree),
+		Err(Error::EmptyList)
+	));
+	for source in ["#\\a", "{#\\space #\\newline #\\tab #\\(}"] {
+		assert_eq!(read_forms(source).unwrap()[0].value.to_string(), source);
+	}
+	assert!(!is_incomplete("(list #\\()"));
+}
+
+#[test]
+fn test_session_save_and_reset() {
+	let mut env = Lenv::new(None, None);
+	let mut session = Session::new(Backend::Tree);
+	session
+		.eval(
+			&mut env,
+			"(def {x} 5)(def {sq} (\\ {n} {* n n} ; squared\n))",
+		)
+		.unwrap();
+	assert_eq!(*session.eval(&mut env, "(sq x)").unwrap(), Lval::Num(25));
+
+	let path = env::temp_dir().join("blispr-test-session.blispr");
+	session
+		.command(&mut env, &format!("save {}", path.display()))
+		.unwrap();
+	let saved = fs::read_to_string(&path).unwrap();
+	assert_eq!(
+		saved,
+		"(def {x} 5)\n(def {sq} (\\ {n} {* n n} ; squared\n))\n"
+	);
+
+	session.command(&mut env, "reset").unwrap();
+	assert!(env.get("sq").is_err());
+	session
+		.command(&mut env, &format!("load {}", path.display()))
+		.unwrap();
+	fs::remove_file(&path).unwrap();
+	assert_eq!(*session.eval(&mut env, "(sq x)").unwrap(), Lval::Num(25));
+
+	assert!(matches!(
+		session.command(&mut env, "bogus"),
+		Err(Error::Command(_))
+	));
+}
+
+#[test]
+fn test_session_input() {
+	let mut env = Lenv::new(None, None);
+	let mut session = Session::new(Backend::Tree);
+	// only known commands are commands, any other :word is a keyword
+	assert_eq!(
+		session.input(&mut env, ":foo").unwrap(),
+		Some(keyword("foo"))
+	);
+	assert!(session
+		.input(&mut env, "(def {x} :reset)")
+		.unwrap()
+		.is_some());
+	assert_eq!(session.input(&mut env, " :reset").unwrap(), None);
+	assert!(env.get("x").is_err());
+}
+
+#[test]
+fn test_config_file() {
+	let mut config = Config::default();
+	config
+		.parse("# comment\nprompt = >>>\nhistory-size = 5 # trailing\n\nedit-mode = vi\nbanner = false\n")
+		.unwrap();
+	assert_eq!(config.prompt, ">>> ");
+	assert_eq!(config.history_size, 5);
+	assert_eq!(config.edit_mode, EditMode::Vi);
+	assert!(!config.banner);
+
+	// a # inside a value is kept, one after a space starts a comment
+	config.parse("prompt = a#b> # comment").unwrap();
+	assert_eq!(config.prompt, "a#b> ");
+
+	// flags go on top of the file, and get the same space
+	let path = env::temp_dir().join("blispr-test-config");
+	fs::write(&path, "prompt = >>>\nhistory-size = 5\n").unwrap();
+	let opt = Opt::parse_from(["blispr", "--prompt", "λ>"]);
+	let loaded = Config::load_from(Some(&path), &opt).unwrap();
+	fs::remove_file(&path).unwrap();
+	assert_eq!(loaded.prompt, "λ> ");
+	assert_eq!(loaded.history_size, 5);
+
+	assert!(matches!(
+		Config::default().parse("colour = yes"),
+		Err(Error::Config(_))
+	));
+}
+
+#[test]
+fn test_shebang() {
+	test_blispr("#!/usr/bin/env blispr\n(+ 1 2)", &Lval::Num(3));
+}
+
+#[test]
+fn test_exit_codes() {
+	let mut env = Lenv::new(None, None);
+	let parse = eval_str(&mut env, "(+ 1", Backend::Tree).unwrap_err();
+	assert_eq!(parse.exit_code(), EXIT_PARSE);
+	let runtime = eval_str(&mut env, "(/ 1 0)", Backend::Tree).unwrap_err();
+	assert_eq!(runtime.exit_code(), EXIT_RUNTIME);
+	let io = file_contents(PathBuf::from("no/such/file.blispr")).unwrap_err();
+	assert_eq!(io.exit_code(), EXIT_IO);
+	let exit = eval_str(&mut env, "(exit 7)", Backend::Tree).unwrap_err();
+	assert_eq!(exit.exit_code(), 7);
+}
+
+#[test]
+fn test_read_forms() {
+	let forms = read_forms("#!/usr/bin/env blispr\n(def {x} 1)\n  x /* two */ {3}\n").unwrap();
+	let found: Vec<(&str, usize, usize)> = forms
+		.iter()
+		.map(|form| (form.source.as_str(), form.line, form.col))
+		.collect();
+	assert_eq!(
+		found,
+		vec![("(def {x} 1)", 2, 1), ("x", 3, 3), ("{3}", 3, 15)]
+	);
+	assert_eq!(forms[2].value.to_string(), "{3}");
+
+	let err = Error::InForm(3, 4, 1, Box::new(Error::DivideByZero));
+	assert_eq!(
+		err.to_string(),
+		"Form 3 at line 4, column 1: Divide by zero"
+	);
+	assert_eq!(err.exit_code(), EXIT_RUNTIME);
+}
+
+#[test]
+fn test_reader_matches_pest() {
+	let programs = [
+		"",
+		"#!/usr/bin/env blispr\n(+ 1 2)",
+		"(def {x} 100) (def {y} 200)\n  (+ x y) ; comment",
+		"(+ 1 /* outer /* inner */ */ 2) #_ (gone) {a #_ b c}",
+		"(list 0xff 0o17 0b1010 1_000 -5 - -x +)",
+		"(list? str->num set! *global* a.b λ a:b)",
+		":kw ':kw 'x '(1 2) {#\\a #\\space #\\( #\\λ}",
+		"(\\ {x & xs} {x})",
+	];
+	for program in programs {
+		let pest = read_forms(program).unwrap();
+		let streamed: Vec<Form> = Reader::new(program.as_bytes())
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert_eq!(pest.len(), streamed.len(), "{program}");
+		for (p, s) in pest.iter().zip(&streamed) {
+			assert_eq!(p.value, s.value, "{program}");
+			assert_eq!(p.source, s.source, "{program}");
+			assert_eq!((p.line, p.col), (s.line, s.col), "{program}");
+		}
+	}
+	for bad in [
+		"(+ 1 2))",
+		"(1.5)",
+		"5x",
+		"(+ 1 9223372036854775808)",
+		"#\\ab",
+		"(]",
+		"#x",
+		"' #_ a b",
+	] {
+		assert!(read_forms(bad).is_err(), "{bad}");
+		assert!(
+			Reader::new(bad.as_bytes()).any(|form| form.is_err()),
+			"{bad}"
+		);
+	}
+}
+
+#[test]
+fn test_reader_streams() {
+	// each form comes out before the reader has looked at the next one
+	let mut reader = Reader::new("(+ 1 2)\n  (+ 1 2))".as_bytes());
+	let first = reader.read_form().unwrap().unwrap();
+	assert_eq!(first.value.to_string(), "(+ 1 2)");
+	assert_eq!(reader.location(), (1, 8));
+	let second = reader.read_form().unwrap().unwrap();
+	assert_eq!((second.line, second.col), (2, 3));
+	assert!(matches!(reader.read_form(), Err(Error::Parse(_))));
+	assert_eq!(reader.location(), (2, 10));
+	// and once it's hit an error it stays stopped
+	assert!(matches!(reader.read_form(), Ok(None)));
+
+	let mut reader = Reader::new("(def {x}\n  {1 2".as_bytes());
+	assert!(matches!(reader.read_form(), Err(Error::Incomplete(2, 7))));
+}
+
+#[test]
+fn test_recovering_reader() {
+	let source = "(def {x} 1))\n(+ x 1.5 2)\n{a (b}\n(+ 1 2)\n(list {1 2";
+	let (forms, diagnostics) = read_recovering(source.as_bytes()).unwrap();
+	let messages: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+	assert_eq!(
+		messages,
+		vec![
+			"line 1, column 12: unexpected `)`",
+			"line 2, column 6: invalid number `1.5`",
+			"line 3, column 6: expected `)` before `}` - unclosed `(` opened at 3:4",
+			"line 5, column 7: unclosed `{` opened at 5:7",
+			"line 5, column 1: unclosed `(` opened at 5:1",
+		]
+	);
+	assert_eq!(diagnostics[1].end, (2, 9));
+	// whatever did read is still there, with the bad parts left out
+	let read: Vec<String> = forms.iter().map(|form| form.value.to_string()).collect();
+	assert_eq!(
+		read,
+		vec![
+			"(def {x} 1)",
+			"(+ x 2)",
+			"{a (b)}",
+			"(+ 1 2)",
+			"(list {1 2})"
+		]
+	);
+
+	// the REPL reports them all and runs none of it
+	let mut env = Lenv::new(None, None);
+	match Session::new(Backend::Tree).eval(&mut env, "(def {y} 1) (+ 1 1.5))") {
+		Err(Error::Syntax(diagnostics)) => assert_eq!(diagnostics.len(), 2),
+		res => panic!("expected syntax errors, got {res:?}"),
+	}
+	assert!(env.get("y").is_err());
+}
+
+#[test]
+fn test_format() {
+	let source = "#!/usr/bin/env blispr\n; adds things up\n(def {add-all}   (\\ {& xs} {eval (join {+} xs)}))  ; varargs\n\n\n\n(add-all 1\n2 /* three */ 3)\n'( ; inner\na b)\n#_  (left   out)\n";
+	let formatted = format_source(source, 30).unwrap();
+	assert_eq!(
+		formatted,
+		"#!/usr/bin/env blispr\n; adds things up\n(def {add-all}\n  (\\ {& xs}\n    {eval (join {+} xs)})) ; varargs\n\n(add-all 1\n         2 /* three */\n         3)\n'( ; inner\n  a b)\n#_(left out)\n"
+	);
+	// formatting is stable, and doesn't change what the program means
+	assert_eq!(format_source(&formatted, 30).unwrap(), formatted);
+	let forms = |s: &str| -> Vec<String> {
+		read_forms(s)
+			.unwrap()
+			.iter()
+			.map(|form| form.value.to_string())
+			.collect()
+	};
+	assert_eq!(forms(&formatted), forms(source));
+	// data fills each line, and anything that fits stays on one
+	assert_eq!(
+		format_source("(def {xs} {1 2 3 4 5 6 7 8 9 10})", 16).unwrap(),
+		"(def {xs}\n  {1 2 3 4 5 6 7\n   8 9 10})\n"
+	);
+	assert_eq!(format_source("(  +  1   2 )", 80).unwrap(), "(+ 1 2)\n");
+	assert!(matches!(
+		format_source("(+ 1 2))", 80),
+		Err(Error::Syntax(_))
+	));
+
+	// values are laid out the same way
+	let mut env = Lenv::new(None, None);
+	let lambda = eval_str(&mut env, "(\\ {x y} {+ (* x x) (* y y)})", Backend::Tree).unwrap();
+	assert_eq!(
+		format_value(&lambda, 0, 20),
+		"(\\ {x y}\n  {+ (* x x)\n     (* y y)})"
+	);
+}
+
+#[test]
+fn test_lint() {
+	let source = "(def {even?} (\\ {n} {odd? n}))\n(def {odd?} (\\ {n _} {even? n}))\n(def {f} (\\ {x x & a b} {+ x y}))\n(cons 1 {2} {3})\n(len)\n(eval {+ nope 1})\n(head {data isn't checked})\n(later)\n(def {later} (\\ {} {1}))\n";
+	let found: Vec<(&str, (usize, usize))> = lint_source(source)
+		.unwrap()
+		.iter()
+		.map(|lint| (lint.check.name(), lint.diagnostic.start))
+		.collect();
+	assert_eq!(
+		found,
+		vec![
+			("duplicate-formal", (3, 16)),
+			("bad-varargs", (3, 18)),
+			("unused-formal", (3, 20)),
+			("unused-formal", (3, 22)),
+			("unknown-symbol", (3, 30)),
+			("wrong-arity", (4, 1)),
+			("wrong-arity", (5, 1)),
+			("unknown-symbol", (6, 10)),
+			("unknown-symbol", (8, 2)),
+		]
+	);
+	// redefining a builtin after a top-level call doesn't excuse the call
+	let redefined = lint_source(
+		"(def {x} 1)\n(cons 1)\n(def {f} (\\ {} {cons 1}))\n(def {cons} (\\ {a} {a}))\n(cons 1)\n",
+	)
+	.unwrap();
+	let found: Vec<(&str, (usize, usize))> = redefined
+		.iter()
+		.map(|lint| (lint.check.name(), lint.diagnostic.start))
+		.collect();
+	assert_eq!(found, vec![("wrong-arity", (2, 1))]);
+	assert_eq!(arity("cons"), Some(2));
+	assert_eq!(arity("exit"), Some(1));
+	assert_eq!(arity("+"), None);
+
+	let syntax = lint_source("(+ 1 2))").unwrap();
+	assert_eq!(syntax.len(), 1);
+	assert_eq!(syntax[0].check, Check::Syntax);
+	assert_eq!(
+		syntax[0].to_json("a.blispr").to_string(),
+		r#"{"code":"syntax-error","column":8,"end_column":9,"end_line":1,"file":"a.blispr","line":1,"message":"unexpected `)`","severity":"error"}"#
+	);
+	assert_eq!(
+		lint_source("(len {1} {2})").unwrap()[0].to_string(),
+		"1:1: error: `len` takes 1 argument, but was given 2 [wrong-arity]"
+	);
+}
+
+#[test]
+fn test_lsp() {
+	let uri = "file:///sq.blispr";
+	let document = json!({ "uri": uri });
+	let at = |line: usize, character: usize| json!({ "textDocument": document, "position": { "line": line, "character": character } });
+	let requests = [
+		json!({ "id": 1, "method": "initialize", "params": {} }),
+		json!({ "method": "initialized", "params": {} }),
+		json!({ "method": "textDocument/didOpen", "params": { "textDocument": {
+			"uri": uri,
+			"text": "(def {sq} (\\ {x} {* x x}))\n(sq (cons 1 {2} {3}))\n(  len {1})\n",
+		}}}),
+		json!({ "id": 2, "method": "textDocument/hover", "params": at(1, 2) }),
+		json!({ "id": 3, "method": "textDocument/hover", "params": at(1, 6) }),
+		json!({ "id": 4, "method": "textDocument/definition", "params": at(1, 1) }),
+		json!({ "id": 5, "method": "textDocument/completion", "params": at(1, 1) }),
+		json!({ "id": 6, "method": "textDocument/formatting", "params": { "textDocument": document } }),
+		json!({ "method": "textDocument/didChange", "params": {
+			"textDocument": document,
+			"contentChanges": [{ "text": "(sq 2" }],
+		}}),
+		json!({ "id": 7, "method": "no/such/method" }),
+		json!({ "method": "textDocument/didOpen", "params": { "textDocument": {
+			"uri": "file:///chars.blispr",
+			"text": "(len\n  {#\\λ #\\𝄞})",
+		}}}),
+		json!({ "id": 9, "method": "textDocument/formatting", "params": { "textDocument": { "uri": "file:///chars.blispr" } } }),
+		json!({ "id": 8, "method": "shutdown" }),
+		json!({ "method": "exit" }),
+	];
+	let mut input = Vec::new();
+	for request in requests {
+		write_message(&mut input, &request).unwrap();
+	}
+	let mut output = Vec::new();
+	assert_eq!(serve(input.as_slice(), &mut output).unwrap(), 0);
+
+	let mut replies = Vec::new();
+	let mut output = output.as_slice();
+	while let Some(body) = read_message(&mut output).unwrap() {
+		replies.push(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+	}
+	let reply = |id: u64| &replies.iter().find(|r| r["id"] == id).unwrap()["result"];
+	assert_eq!(reply(1)["capabilities"]["hoverProvider"], true);
+	let diagnostics: Vec<&serde_json::Value> = replies
+		.iter()
+		.filter(|r| r["method"] == "textDocument/publishDiagnostics")
+		.collect();
+	assert_eq!(
+		diagnostics[0]["params"]["diagnostics"][0],
+		json!({
+			"range": { "start": { "line": 1, "character": 4 }, "end": { "line": 1, "character": 20 } },
+			"severity": 1,
+			"code": "wrong-arity",
+			"source": "blispr",
+			"message": "`cons` takes 2 arguments, but was given 3",
+		})
+	);
+	assert_eq!(
+		diagnostics[1]["params"]["diagnostics"][0]["message"],
+		"unclosed `(` opened at 1:1"
+	);
+	assert_eq!(
+		reply(2)["contents"]["value"],
+		"`sq` is defined as\n```blispr\n(\\ {x} {* x x})\n```"
+	);
+	assert_eq!(
+		reply(3)["contents"]["value"],
+		"```blispr\n(cons x {list})\n```\nAttach x to the front of list"
+	);
+	assert_eq!(
+		*reply(4),
+		json!({ "uri": uri, "range": {
+			"start": { "line": 0, "character": 6 },
+			"end": { "line": 0, "character": 8 },
+		}})
+	);
+	let labels: Vec<&str> = reply(5)
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|item| item["label"].as_str().unwrap())
+		.collect();
+	assert!(labels.contains(&"cons") && labels.contains(&"sq"));
+	assert_eq!(
+		reply(6)[0]["newText"],
+		"(def {sq} (\\ {x} {* x x}))\n(sq (cons 1 {2} {3}))\n(len {1})\n"
+	);
+	// the edit covers exactly the document, counting in UTF-16 like the client
+	assert_eq!(
+		reply(6)[0]["range"]["end"],
+		json!({ "line": 3, "character": 0 })
+	);
+	assert_eq!(
+		reply(9)[0]["range"]["end"],
+		json!({ "line": 1, "character": 13 })
+	);
+	let unknown = replies.iter().find(|r| r["id"] == 7).unwrap();
+	assert_eq!(unknown["error"]["code"], -32601);
+}
+
+// the debugger's output, kept where the test can still read it
+struct Transcript(Rc<RefCell<Vec<u8>>>);
+impl io::Write for Transcript {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.borrow_mut().write(buf)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[test]
+fn test_debugger() {
+	let out = Rc::new(RefCell::new(Vec::new()));
+	let commands = "args\ns\nbt\nenv\nn\nn\nargs\np (sq b)\np (nope)\nb\nq\n";
+	let debugger = Debugger::new(
+		Box::new(commands.as_bytes()),
+		Box::new(Transcript(Rc::clone(&out))),
+	);
+	let mut env = Lenv::new(None, None);
+	env.set_debugger(Some(Rc::new(debugger)));
+	let mut session = Session::new(Backend::Tree);
+	session
+		.eval(
+			&mut env,
+			"(def {sq} (\\ {x} {* x x})) (def {sumsq} (\\ {a b} {+ (sq a) (sq b)}))",
+		)
+		.unwrap();
+	session.command(&mut env, "break sumsq").unwrap();
+	session.command(&mut env, "break 3").unwrap();
+	assert_eq!(
+		*session.eval(&mut env, "(sumsq 3 4)").unwrap(),
+		Lval::Num(25)
+	);
+	assert_eq!(
+		String::from_utf8(out.take()).unwrap(),
+		"stopped at (sumsq 3 4) (breakpoint sumsq)
+debug> 1: 3
+2: 4
+debug> stopped at (sq a) (step)
+debug> #0 (sq a)
+#1 (sumsq 3 4)
+debug> #0 local: a = 3, b = 4
+#1 global: sq = (\\ {x} {* x x}), sumsq = (\\ {a b} {+ (sq a) (sq b)})
+debug> stopped at (sq b) (step)
+debug> stopped at (+ (sq a) (sq b)) (step)
+debug> 1: 9
+2: 16
+debug> 16
+debug> Error: Unknown function nope
+debug> sumsq
+line 3
+debug> "
+	);
+
+	// q removed the breakpoints, but (break ()) still stops - with the commands used up, it carries on
+	assert!(matches!(
+		session.command(&mut env, "clear sumsq"),
+		Err(Error::Command(_))
+	));
+	assert_eq!(
+		*session
+			.eval(&mut env, "(sumsq 1 (len (list (break ()))))")
+			.unwrap(),
+		Lval::Num(2)
+	);
+	session.command(&mut env, "step (sq 2)").unwrap();
+	assert_eq!(
+		String::from_utf8(out.take()).unwrap(),
+		"stopped at (break)\ndebug> \nstopped at (sq 2) (step)\ndebug> \n"
+	);
+
+	// a line breakpoint stops at the calls on that line, even inside a lambda defined further up
+	let debugger = Debugger::new(
+		Box::new("bt\nc\n".as_bytes()),
+		Box::new(Transcript(Rc::clone(&out))),
+	);
+	let mut env = Lenv::new(None, None);
+	env.set_debugger(Some(Rc::new(debugger)));
+	let path = env::temp_dir().join("blispr-test-debugger.blispr");
+	fs::write(&path, "(def {sq} (\\ {x}\n  {* x x}))\n(sq 3)\n").unwrap();
+	session.command(&mut env, "break 2").unwrap();
+	session
+		.command(&mut env, &format!("load {}", path.display()))
+		.unwrap();
+	assert_eq!(
+		String::from_utf8(out.take()).unwrap(),
+		"stopped at (* x x) (breakpoint line 2)\ndebug> #0 (* x x)\n#1 (sq 3)\ndebug> "
+	);
+	// typed in at the prompt, there are no lines to match
+	assert_eq!(*session.eval(&mut env, "(sq 4)").unwrap(), Lval::Num(16));
+	assert_eq!(String::from_utf8(out.take()).unwrap(), "");
+
+	assert_eq!(Breakpoint::parse("12"), Breakpoint::Line(12));
+	assert_eq!(
+		Breakpoint::parse("fib"),
+		Breakpoint::Function("fib".to_string())
+	);
+}
+
+#[test]
+fn test_debugger_vm() {
+	let out = Rc::new(RefCell::new(Vec::new()));
+	let mut env = Lenv::new(None, None);
+	eval_str(&mut env, "(def {sq} (\\ {x} {* x x}))", Backend::Vm).unwrap();
+	// the VM makes its calls without the tree-walker
+	let mut session = Session::new(Backend::Vm);
+	assert!(matches!(
+		session.command(&mut env, "break sq"),
+		Err(Error::Command(_))
+	));
+	// and the debugger can't be asked for alongside it
+	for args in [
+		["blispr", "--backend", "vm", "--debugger"],
+		["blispr", "--break=sq", "-b", "vm"],
+	] {
+		assert!(Opt::parse_from(args).check().is_err());
+	}
+	assert_eq!(
+		Opt::parse_from(["blispr", "--debugger"])
+			.check()
+			.unwrap()
+			.backend(),
+		Backend::Tree
+	);
+	// but (break ()) still stops
+	let debugger = Debugger::new(
+		Box::new("p (sq 5)\nc\n".as_bytes()),
+		Box::new(Transcript(Rc::clone(&out))),
+	);
+	env.set_debugger(Some(Rc::new(debugger)));
+	assert_eq!(
+		*session
+			.eval(&mut env, "(+ 1 (len (list (break ()))))")
+			.unwrap(),
+		Lval::Num(2)
+	);
+	assert_eq!(
+		String::from_utf8(out.take()).unwrap(),
+		"stopped at (break)\ndebug> 25\ndebug> "
+	);
+}
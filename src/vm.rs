@@ -0,0 +1,107 @@
+// A stack machine that runs the Chunks built in compile.rs
+// Builtins are shared with the tree-walker, only evaluation and lambda calls differ
+use crate::{
+	compile::{compile, Chunk, Op},
+	error::{BlisprResult, Error, Result},
+	eval::{bind_formals, builtin_break, builtin_def, builtin_printenv},
+	lenv::Lenv,
+	lval::{add, lambda, pop, sexpr, Func, Lval},
+};
+use log::debug;
+
+// Run a compiled chunk - slots holds the values for chunk.locals
+fn run(e: &mut Lenv, chunk: &Chunk, slots: &mut [Box<Lval>]) -> BlisprResult {
+	debug!("vm_run:\n{}", chunk);
+	let mut stack: Vec<Box<Lval>> = Vec::new();
+	let budget = e.budget();
+	for op in &chunk.code {
+		budget.step()?;
+		match *op {
+			Op::Const(i) => stack.push(Box::new(chunk.constants[i].clone())),
+			Op::Load(i) => stack.push(e.get(&chunk.names[i])?),
+			Op::Local(i) => stack.push(slots[i].clone()),
+			Op::Call(n) => {
+				let mut args = sexpr();
+				for arg in stack.drain(stack.len() - n..) {
+					add(&mut args, &arg)?;
+				}
+				let f = stack.pop().ok_or(Error::NoChildren)?;
+				debug!("vm_run: Calling function {:?} on {:?}", f, args);
+				// def, or a def run through eval, may have shadowed one of our formals
+				let redefines = matches!(
+					&*f,
+					Lval::Fun(Func::Builtin(name, _)) if name == "def" || name == "eval"
+				);
+				stack.push(vm_call(e, *f, &mut args)?);
+				// so reload the slots from the env
+				if redefines {
+					for (slot, name) in slots.iter_mut().zip(&chunk.locals) {
+						*slot = e.get(name)?;
+					}
+				}
+			},
+			Op::Pop => {
+				stack.pop();
+			},
+		}
+	}
+	stack.pop().ok_or(Error::NoChildren)
+}
+
+// The VM counterpart of lval_call
+fn vm_call(e: &mut Lenv, f: Lval, args: &mut Lval) -> BlisprResult {
+	e.budget().call(|| vm_call_unmetered(e, f, args))
+}
+
+fn vm_call_unmetered(e: &mut Lenv, f: Lval, args: &mut Lval) -> BlisprResult {
+	match f {
+		Lval::Fun(Func::Builtin(name, fp)) => {
+			e.capabilities().check(&name)?;
+			match name.as_str() {
+				"eval" => {
+					// same rules as builtin_eval - a leading qexpr is run as an sexpr
+					let first = pop(args, 0)?;
+					if let Lval::Qexpr(ref cells) = *first {
+						vm_eval(e, &Lval::Sexpr(cells.clone()))
+					} else {
+						add(args, &first)?;
+						vm_eval(e, args)
+					}
+				},
+				"def" => builtin_def(e, args),
+				"printenv" => builtin_printenv(e),
+				"break" => builtin_break(e),
+				_ => fp(args),
+			}
+		},
+		Lval::Fun(Func::Lambda(env, mut formals, body, compiled)) => {
+			let new_env = bind_formals(&mut formals, args)?;
+			if formals.len()? > 0 {
+				debug!("vm_call: Returning partially applied lambda");
+				return Ok(lambda(new_env, formals, body));
+			}
+			// every name bound by the call gets a slot
+			let mut locals: Vec<String> = new_env.keys().cloned().collect();
+			let mut local_env = Lenv::new(Some(new_env), Some(e));
+			for (k, v) in env.bindings().borrow().iter() {
+				locals.push(k.clone());
+				local_env.put(k.clone(), v.clone());
+			}
+			locals.sort();
+			locals.dedup();
+			let mut slots = locals
+				.iter()
+				.map(|name| local_env.get(name))
+				.collect::<Result<Vec<Box<Lval>>>>()?;
+			let chunk = compiled.chunk(&body, &locals);
+			run(&mut local_env, &chunk, &mut slots)
+		},
+		_ => Err(Error::WrongType("Function".to_string(), format!("{f:?}"))),
+	}
+}
+
+// Fully evaluate an `Lval` by compiling it and running the result
+pub fn vm_eval(e: &mut Lenv, v: &Lval) -> BlisprResult {
+	let chunk = compile(v, &[]);
+	run(e, &chunk, &mut [])
+}